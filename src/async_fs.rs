@@ -0,0 +1,361 @@
+use crate::cache::Cache;
+use crate::defs::{BlockContents, Ext4DirEntry, Ext4Inode, Ext4SuperBlock};
+use crate::device::{BlockDevice, DEFAULT_SECTOR_SIZE};
+use crate::file::{seek_position, FileLayout, Span};
+use crate::fs_parser::{Err, Ext4Fs, LookupError};
+use futures_core::Stream;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Entries a `ReadDir` stream reads ahead of its consumer
+const READ_DIR_AHEAD: usize = 256;
+
+/// `BlockDevice` for storage read asynchronously, e.g. over the network
+pub trait AsyncBlockDevice: Send + Sync + 'static {
+    /// Fills `buf` with the bytes starting at `offset`, failing when they run past
+    /// the end of the device
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Size of the device in bytes
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Smallest unit the device reads, file system blocks are a multiple of it
+    fn block_size(&self) -> u64 {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    /// The device to read metadata from on the blocking pool when it can be read
+    /// without the runtime, saving a trip through it
+    fn as_sync(&self) -> Option<&dyn BlockDevice> {
+        None
+    }
+}
+
+/// A `BlockDevice` read on tokio's blocking pool, devices held in memory are read
+/// in place
+#[derive(Debug)]
+pub struct BlockingDevice<D: BlockDevice>(pub Arc<D>);
+
+impl<D: BlockDevice> BlockingDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self(Arc::new(device))
+    }
+}
+
+impl<D: BlockDevice + 'static> AsyncBlockDevice for BlockingDevice<D> {
+    async fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.0.as_slice().is_some() {
+            return self.0.read_exact_at(offset, buf);
+        }
+        let device = self.0.clone();
+        let len = buf.len();
+        let data = task::spawn_blocking(move || {
+            let mut data = vec![0; len];
+            device.read_exact_at(offset, &mut data).map(|()| data)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn block_size(&self) -> u64 {
+        self.0.block_size()
+    }
+
+    fn as_sync(&self) -> Option<&dyn BlockDevice> {
+        Some(&*self.0)
+    }
+}
+
+/// An async device as the sync core reads it, only from the blocking pool since
+/// reads wait on the runtime
+struct SyncDevice<A> {
+    device: Arc<A>,
+    runtime: Handle,
+}
+
+impl<A: AsyncBlockDevice> BlockDevice for SyncDevice<A> {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self.device.as_sync() {
+            Some(device) => device.read_exact_at(offset, buf),
+            None => self.runtime.block_on(self.device.read_exact_at(offset, buf)),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.device.len()
+    }
+
+    fn block_size(&self) -> u64 {
+        self.device.block_size()
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        self.device.as_sync()?.as_slice()
+    }
+}
+
+/// A file system read from an `AsyncBlockDevice` for use in tokio tasks. Metadata
+/// is parsed by the sync core on the blocking pool, file data is read from the
+/// device directly. Clones share the file system.
+pub struct AsyncExt4Fs<A: AsyncBlockDevice> {
+    fs: Arc<Ext4Fs<SyncDevice<A>>>,
+    device: Arc<A>,
+}
+
+impl<A: AsyncBlockDevice> Clone for AsyncExt4Fs<A> {
+    fn clone(&self) -> Self {
+        Self { fs: self.fs.clone(), device: self.device.clone() }
+    }
+}
+
+impl<A: AsyncBlockDevice> AsyncExt4Fs<A> {
+    /// Opens the file system on `device`, from within a tokio runtime
+    pub async fn new(device: A) -> Result<Self, Err> {
+        Self::mount(device, None).await
+    }
+
+    /// `new` keeping metadata in `cache`, which spares the device most reads
+    pub async fn with_cache(device: A, cache: Arc<Cache>) -> Result<Self, Err> {
+        Self::mount(device, Some(cache)).await
+    }
+
+    async fn mount(device: A, cache: Option<Arc<Cache>>) -> Result<Self, Err> {
+        let device = Arc::new(device);
+        let sync_device = SyncDevice { device: device.clone(), runtime: Handle::current() };
+        let fs = blocking(move || {
+            let mut fs = Ext4Fs::new(sync_device)?;
+            if let Some(cache) = cache {
+                fs.set_cache(cache);
+            }
+            Ok::<_, Err>(fs)
+        })
+        .await?;
+        Ok(Self { fs: Arc::new(fs), device })
+    }
+
+    pub fn super_block(&self) -> &Ext4SuperBlock {
+        self.fs.super_block()
+    }
+
+    pub fn device(&self) -> &A {
+        &self.device
+    }
+
+    /// Runs `f` on the sync core from the blocking pool
+    async fn with_fs<T: Send + 'static>(&self, f: impl FnOnce(&Ext4Fs<SyncDevice<A>>) -> T + Send + 'static) -> T {
+        let fs = self.fs.clone();
+        blocking(move || f(&fs)).await
+    }
+
+    pub async fn get_inode(&self, i_no: u64) -> Option<Ext4Inode> {
+        self.with_fs(move |fs| fs.get_inode(i_no)).await
+    }
+
+    /// See `Ext4Fs::lookup`
+    pub async fn lookup(&self, path: &str, follow_symlinks: bool) -> Result<(u64, Ext4Inode), LookupError> {
+        let path = path.to_string();
+        self.with_fs(move |fs| fs.lookup(&path, follow_symlinks)).await
+    }
+
+    /// See `Ext4Fs::read_link`
+    pub async fn read_link(&self, inode: &Ext4Inode) -> Option<Vec<u8>> {
+        let inode = *inode;
+        self.with_fs(move |fs| fs.read_link(&inode)).await
+    }
+
+    /// Entries of the directory `dir` in on-disk order, `.` and `..` included and
+    /// unused slots left out. Blocks are read ahead of the consumer, the stream is
+    /// empty when `dir` is not a directory.
+    pub fn read_dir(&self, dir: &Ext4Inode) -> ReadDir {
+        let (sender, receiver) = mpsc::channel(READ_DIR_AHEAD);
+        let fs = self.fs.clone();
+        let dir = *dir;
+        let task = task::spawn_blocking(move || {
+            if let Some(BlockContents::Dentries(entries)) = fs.get_inode_block_contents(&dir) {
+                for d_entry in entries.filter(|d_entry| d_entry.inode != 0) {
+                    if sender.blocking_send(d_entry).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        ReadDir { receiver, task: Some(task) }
+    }
+
+    /// A handle reading the contents of `inode`
+    pub async fn open(&self, inode: &Ext4Inode) -> AsyncExt4File<A> {
+        let inode = *inode;
+        let layout = self.with_fs(move |fs| FileLayout::load(fs, &inode)).await;
+        AsyncExt4File {
+            device: self.device.clone(),
+            block_size: self.fs.block_size(),
+            layout,
+            pos: 0,
+            buffer: (0, Vec::new()),
+            pending: None,
+        }
+    }
+
+    /// `open` on the file `path` resolves to, symlinks followed
+    pub async fn open_path(&self, path: &str) -> Result<AsyncExt4File<A>, LookupError> {
+        let (_, inode) = self.lookup(path, true).await?;
+        Ok(self.open(&inode).await)
+    }
+}
+
+/// Runs `f` on the blocking pool, passing its panics on
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    joined(task::spawn_blocking(f).await)
+}
+
+/// The value of a joined blocking task, resuming its panic if it had one
+fn joined<T>(result: Result<T, task::JoinError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => match error.try_into_panic() {
+            Ok(payload) => panic::resume_unwind(payload),
+            Err(error) => panic!("blocking read did not complete: {error}"),
+        },
+    }
+}
+
+/// Directory entries read by `AsyncExt4Fs::read_dir`
+pub struct ReadDir {
+    receiver: mpsc::Receiver<Ext4DirEntry>,
+    /// the reading task, joined once the channel closes so that a panic does not
+    /// pass for the end of the listing
+    task: Option<task::JoinHandle<()>>,
+}
+
+impl Stream for ReadDir {
+    type Item = Ext4DirEntry;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(d_entry) = ready!(this.receiver.poll_recv(cx)) {
+            return Poll::Ready(Some(d_entry));
+        }
+        if let Some(task) = &mut this.task {
+            let result = ready!(Pin::new(task).poll(cx));
+            this.task = None;
+            joined(result);
+        }
+        Poll::Ready(None)
+    }
+}
+
+type PendingRead = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// The contents of a file read asynchronously, see `AsyncExt4Fs::open`. Extents
+/// are read whole, up to `file::MAX_READ` bytes. Holes and unwritten extents
+/// read as zeros.
+pub struct AsyncExt4File<A: AsyncBlockDevice> {
+    device: Arc<A>,
+    block_size: u64,
+    layout: FileLayout,
+    pos: u64,
+    /// file offset and data of the last device read
+    buffer: (u64, Vec<u8>),
+    /// device read in flight and the file offset it starts at
+    pending: Option<(u64, PendingRead)>,
+}
+
+impl<A: AsyncBlockDevice> AsyncExt4File<A> {
+    pub fn size(&self) -> u64 {
+        self.layout.size()
+    }
+
+    /// See `Ext4File::next_data`
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        self.layout.next_data(offset)
+    }
+
+    /// See `Ext4File::next_hole`
+    pub fn next_hole(&self, offset: u64) -> Option<u64> {
+        self.layout.next_hole(offset)
+    }
+}
+
+impl<A: AsyncBlockDevice> AsyncRead for AsyncExt4File<A> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let wanted = (buf.remaining() as u64).min(this.layout.size().saturating_sub(this.pos)) as usize;
+        if wanted == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let (start, data) = &this.buffer;
+            if let Some(src) = this.pos.checked_sub(*start).and_then(|skip| data.get(skip as usize..)).filter(|src| !src.is_empty()) {
+                let len = wanted.min(src.len());
+                buf.put_slice(&src[..len]);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some((start, read)) = &mut this.pending {
+                let start = *start;
+                let data = ready!(read.as_mut().poll(cx))?;
+                this.buffer = (start, data);
+                this.pending = None;
+                continue;
+            }
+            let len = match this.layout.span(this.pos) {
+                Span::Inline(src) => {
+                    let len = wanted.min(src.len());
+                    buf.put_slice(&src[..len]);
+                    len
+                }
+                Span::Zeros(zeros) => {
+                    let len = wanted.min(zeros as usize);
+                    buf.initialize_unfilled_to(len).fill(0);
+                    buf.advance(len);
+                    len
+                }
+                Span::Blocks { start, physical, count } => {
+                    let device = this.device.clone();
+                    let (offset, len) = (physical * this.block_size, count * this.block_size);
+                    let read = async move {
+                        let mut data = vec![0; len as usize];
+                        device.read_exact_at(offset, &mut data).await.map(|()| data)
+                    };
+                    this.pending = Some((start, Box::pin(read)));
+                    continue;
+                }
+            };
+            this.pos += len as u64;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl<A: AsyncBlockDevice> AsyncSeek for AsyncExt4File<A> {
+    /// Positions past the end are allowed and read nothing
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.pos = seek_position(this.pos, this.layout.size(), position)?;
+        // a read in flight elsewhere would only be waited for
+        this.pending = None;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
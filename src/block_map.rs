@@ -0,0 +1,325 @@
+use crate::defs::{
+    Ext4Extent, Ext4ExtentHeader, Ext4ExtentIdx, Ext4Inode, InodeFlags, EXT4_DIND_BLOCK, EXT4_IND_BLOCK,
+    EXT4_NDIR_BLOCKS, EXT4_N_BLOCKS, EXT4_TIND_BLOCK,
+};
+use crate::cache::BlockKind;
+use crate::device::{BlockBytes, BlockDevice};
+use crate::fs_parser::Ext4Fs;
+use nom_derive::Parse;
+
+/// Deepest extent tree the kernel will build
+const EXT4_MAX_EXTENT_DEPTH: u16 = 5;
+
+/// Largest block size ext4 supports, used to hand out zero-filled holes
+pub const EXT4_MAX_BLOCK_SIZE: usize = 65536;
+pub static ZERO_BLOCK: [u8; EXT4_MAX_BLOCK_SIZE] = [0; EXT4_MAX_BLOCK_SIZE];
+
+/// A run of logically contiguous file blocks backed by contiguous physical blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRun {
+    /// first logical block of the run
+    pub logical: u64,
+    /// physical block backing `logical`
+    pub physical: u64,
+    /// number of blocks in the run
+    pub len: u64,
+    /// unwritten extent, reads back as zeros
+    pub uninit: bool,
+}
+
+impl BlockRun {
+    pub fn contains(&self, logical: u64) -> bool {
+        self.logical <= logical && logical < self.logical + self.len
+    }
+}
+
+/// Sorted runs of an inode, used to translate logical blocks into physical ones.
+#[derive(Debug, Clone, Default)]
+pub struct BlockMap {
+    pub runs: Vec<BlockRun>,
+}
+
+impl BlockMap {
+    pub fn new(mut runs: Vec<BlockRun>) -> Self {
+        runs.sort_by_key(|run| run.logical);
+        Self { runs }
+    }
+
+    /// Returns the run covering `logical`, `None` means the block is a hole.
+    pub fn find(&self, logical: u64) -> Option<&BlockRun> {
+        let index = self.runs.partition_point(|run| run.logical + run.len <= logical);
+        self.runs.get(index).filter(|run| run.contains(logical))
+    }
+
+    /// Parts of the runs that fall within `len` blocks from `logical`
+    pub fn range(&self, logical: u64, len: u64) -> impl Iterator<Item = BlockRun> + '_ {
+        let end = logical.saturating_add(len);
+        self.runs.iter().filter_map(move |run| {
+            let start = run.logical.max(logical);
+            let stop = (run.logical + run.len).min(end);
+            (start < stop).then(|| BlockRun {
+                logical: start,
+                physical: run.physical + start - run.logical,
+                len: stop - start,
+                uninit: run.uninit,
+            })
+        })
+    }
+
+    /// Maps `run` in place of whatever its logical range was mapped to
+    pub fn map(&mut self, run: BlockRun) {
+        self.unmap(run.logical, run.len);
+        let index = self.runs.partition_point(|other| other.logical < run.logical);
+        self.runs.insert(index, run);
+    }
+
+    /// Turns `len` blocks from `logical` into a hole, splitting the runs around it
+    pub fn unmap(&mut self, logical: u64, len: u64) {
+        let end = logical.saturating_add(len);
+        let mut runs = Vec::with_capacity(self.runs.len() + 1);
+        for run in self.runs.drain(..) {
+            let run_end = run.logical + run.len;
+            if run_end <= logical || end <= run.logical {
+                runs.push(run);
+                continue;
+            }
+            if run.logical < logical {
+                runs.push(BlockRun { len: logical - run.logical, ..run });
+            }
+            if end < run_end {
+                runs.push(BlockRun { logical: end, physical: run.physical + end - run.logical, len: run_end - end, ..run });
+            }
+        }
+        self.runs = runs;
+    }
+}
+
+pub fn i_block_bytes(inode: &Ext4Inode) -> [u8; EXT4_N_BLOCKS * 4] {
+    let mut bytes = [0; EXT4_N_BLOCKS * 4];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(inode.i_block.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Walks the extent tree rooted in `i_block` and collects its leaf extents.
+pub fn extent_runs<D: BlockDevice>(inode: &Ext4Inode, fs: &Ext4Fs<D>) -> Vec<BlockRun> {
+    let mut runs = Vec::new();
+    walk_extent_node(&i_block_bytes(inode), fs, EXT4_MAX_EXTENT_DEPTH, &mut runs);
+    runs
+}
+
+fn walk_extent_node<D: BlockDevice>(node: &[u8], fs: &Ext4Fs<D>, max_depth: u16, runs: &mut Vec<BlockRun>) -> Option<()> {
+    let (mut rest, header) = Ext4ExtentHeader::parse(node).ok()?;
+    if !header.is_header() || header.eh_depth > max_depth {
+        return None;
+    }
+    for _ in 0..header.eh_entries {
+        if header.eh_depth == 0 {
+            let (next, extent) = Ext4Extent::parse(rest).ok()?;
+            rest = next;
+            runs.push(BlockRun {
+                logical: extent.ee_block as u64,
+                physical: extent.ee_start(),
+                len: extent.len() as u64,
+                uninit: extent.is_uninit(),
+            });
+        } else {
+            let (next, index) = Ext4ExtentIdx::parse(rest).ok()?;
+            rest = next;
+            let child = fs.get_block_as(index.ei_leaf(), BlockKind::MapNode)?;
+            walk_extent_node(&child, fs, header.eh_depth - 1, runs);
+        }
+    }
+    Some(())
+}
+
+/// Follows the direct, indirect, double and triple indirect pointers of an
+/// ext2/ext3 style inode. Zero pointers are holes.
+pub fn indirect_runs<D: BlockDevice>(inode: &Ext4Inode, fs: &Ext4Fs<D>) -> Vec<BlockRun> {
+    pointer_runs(&inode.i_block, fs.block_size(), &|block| fs.get_block_as(block, BlockKind::MapNode))
+}
+
+/// `indirect_runs` over the pointers of `i_block`, reading indirect blocks with `read`
+fn pointer_runs<'a>(i_block: &[u32; EXT4_N_BLOCKS], block_size: u64, read: &impl Fn(u64) -> Option<BlockBytes<'a>>) -> Vec<BlockRun> {
+    let mut runs = Vec::new();
+    for (logical, &physical) in i_block[..EXT4_NDIR_BLOCKS].iter().enumerate() {
+        push_block(&mut runs, logical as u64, physical as u64);
+    }
+
+    let per_block = block_size / 4;
+    let mut logical = EXT4_NDIR_BLOCKS as u64;
+    for (level, index) in [EXT4_IND_BLOCK, EXT4_DIND_BLOCK, EXT4_TIND_BLOCK].into_iter().enumerate() {
+        let level = level as u32 + 1;
+        walk_indirect_block(i_block[index] as u64, level, logical, per_block, read, &mut runs);
+        logical += per_block.pow(level);
+    }
+    runs
+}
+
+fn walk_indirect_block<'a>(
+    block: u64,
+    level: u32,
+    logical: u64,
+    per_block: u64,
+    read: &impl Fn(u64) -> Option<BlockBytes<'a>>,
+    runs: &mut Vec<BlockRun>,
+) {
+    if block == 0 {
+        return;
+    }
+    let Some(pointers) = read(block) else {
+        return;
+    };
+    let span = per_block.pow(level - 1);
+    for (index, pointer) in pointers.chunks_exact(4).enumerate() {
+        let child = u32::from_le_bytes(pointer.try_into().unwrap()) as u64;
+        let child_logical = logical + index as u64 * span;
+        if level == 1 {
+            push_block(runs, child_logical, child);
+        } else {
+            walk_indirect_block(child, level - 1, child_logical, per_block, read, runs);
+        }
+    }
+}
+
+fn push_block(runs: &mut Vec<BlockRun>, logical: u64, physical: u64) {
+    if physical == 0 {
+        return;
+    }
+    if let Some(last) = runs.last_mut()
+        && last.logical + last.len == logical
+        && last.physical + last.len == physical
+    {
+        last.len += 1;
+        return;
+    }
+    runs.push(BlockRun { logical, physical, len: 1, uninit: false });
+}
+
+/// Blocks holding the map itself rather than file data: the extent tree nodes
+/// below `i_block`, or the indirect pointer blocks of every level
+pub(crate) fn map_node_blocks<D: BlockDevice>(inode: &Ext4Inode, fs: &Ext4Fs<D>) -> Vec<u64> {
+    if inode.i_flags.contains(InodeFlags::EXTENTS) {
+        let mut blocks = Vec::new();
+        walk_extent_index(&i_block_bytes(inode), fs, EXT4_MAX_EXTENT_DEPTH, &mut blocks);
+        blocks
+    } else {
+        pointer_nodes(&inode.i_block, &|block| fs.get_block_as(block, BlockKind::MapNode))
+    }
+}
+
+fn walk_extent_index<D: BlockDevice>(node: &[u8], fs: &Ext4Fs<D>, max_depth: u16, blocks: &mut Vec<u64>) -> Option<()> {
+    let (mut rest, header) = Ext4ExtentHeader::parse(node).ok()?;
+    if !header.is_header() || header.eh_depth == 0 || header.eh_depth > max_depth {
+        return None;
+    }
+    for _ in 0..header.eh_entries {
+        let (next, index) = Ext4ExtentIdx::parse(rest).ok()?;
+        rest = next;
+        blocks.push(index.ei_leaf());
+        if let Some(child) = fs.get_block_as(index.ei_leaf(), BlockKind::MapNode) {
+            walk_extent_index(&child, fs, header.eh_depth - 1, blocks);
+        }
+    }
+    Some(())
+}
+
+/// Indirect, double and triple indirect blocks reachable from `i_block`
+fn pointer_nodes<'a>(i_block: &[u32; EXT4_N_BLOCKS], read: &impl Fn(u64) -> Option<BlockBytes<'a>>) -> Vec<u64> {
+    let mut blocks = Vec::new();
+    for (level, index) in [EXT4_IND_BLOCK, EXT4_DIND_BLOCK, EXT4_TIND_BLOCK].into_iter().enumerate() {
+        walk_pointer_nodes(i_block[index] as u64, level as u32 + 1, read, &mut blocks);
+    }
+    blocks
+}
+
+fn walk_pointer_nodes<'a>(block: u64, level: u32, read: &impl Fn(u64) -> Option<BlockBytes<'a>>, blocks: &mut Vec<u64>) {
+    if block == 0 {
+        return;
+    }
+    blocks.push(block);
+    if level == 1 {
+        return;
+    }
+    let Some(pointers) = read(block) else {
+        return;
+    };
+    for pointer in pointers.chunks_exact(4) {
+        walk_pointer_nodes(u32::from_le_bytes(pointer.try_into().unwrap()) as u64, level - 1, read, blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn run(logical: u64, physical: u64, len: u64) -> BlockRun {
+        BlockRun { logical, physical, len, uninit: false }
+    }
+
+    /// Blocks 0-1 and 5-7, with a hole between them
+    fn block_map() -> BlockMap {
+        BlockMap::new(vec![run(5, 200, 3), run(0, 100, 2)])
+    }
+
+    #[test]
+    fn find() {
+        let block_map = block_map();
+        assert_eq!(block_map.find(1), Some(&run(0, 100, 2)));
+        assert_eq!(block_map.find(2), None);
+        assert_eq!(block_map.find(7), Some(&run(5, 200, 3)));
+        assert_eq!(block_map.find(8), None);
+    }
+
+    #[test]
+    fn range() {
+        let block_map = block_map();
+        assert_eq!(block_map.range(1, 6).collect::<Vec<_>>(), [run(1, 101, 1), run(5, 200, 2)]);
+        assert_eq!(block_map.range(2, 3).count(), 0);
+        assert_eq!(block_map.range(6, u64::MAX).collect::<Vec<_>>(), [run(6, 201, 2)]);
+    }
+
+    #[test]
+    fn unmap_middle_of_run() {
+        let mut block_map = block_map();
+        block_map.unmap(6, 1);
+        assert_eq!(block_map.runs, [run(0, 100, 2), run(5, 200, 1), run(7, 202, 1)]);
+    }
+
+    #[test]
+    fn map_over_two_runs() {
+        let mut block_map = block_map();
+        let uninit = BlockRun { uninit: true, ..run(1, 500, 5) };
+        block_map.map(uninit);
+        assert_eq!(block_map.runs, [run(0, 100, 1), uninit, run(6, 201, 2)]);
+        assert_eq!(block_map.find(2), Some(&uninit));
+    }
+
+    #[test]
+    fn indirect_pointers() {
+        let pointers = |words: &[(usize, u32)]| {
+            let mut block = vec![0; 1024];
+            for &(index, word) in words {
+                block[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+            }
+            block
+        };
+        // logical 11 continues from the direct pointers into the indirect block
+        let blocks = HashMap::from([
+            (50, pointers(&[(0, 21), (1, 22), (3, 30)])),
+            (60, pointers(&[(0, 61)])),
+            (61, pointers(&[(0, 40)])),
+        ]);
+        let mut i_block = [0; EXT4_N_BLOCKS];
+        i_block[..3].copy_from_slice(&[10, 11, 12]);
+        i_block[11] = 20;
+        i_block[EXT4_IND_BLOCK] = 50;
+        i_block[EXT4_DIND_BLOCK] = 60;
+        let read = |block| blocks.get(&block).map(|data| BlockBytes::Borrowed(data));
+        let runs = pointer_runs(&i_block, 1024, &read);
+        assert_eq!(runs, [run(0, 10, 3), run(11, 20, 3), run(15, 30, 1), run(12 + 256, 40, 1)]);
+        assert_eq!(pointer_nodes(&i_block, &read), [50, 60, 61]);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::block_map::{BlockMap, BlockRun};
+use crate::defs::{Ext4Inode, EXT4_N_BLOCKS};
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Budget of `CacheConfig::default()`
+pub const DEFAULT_CACHE_BUDGET: usize = 64 << 20;
+
+/// What a cached raw block is read for, statistics are kept per kind. File data
+/// is not cached so reading large files does not flush the metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// bitmaps, descriptors, inode tables, attribute blocks and the like
+    Metadata,
+    /// extent tree nodes and indirect blocks
+    MapNode,
+    DirBlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// bytes the cached items may take, least recently used ones are evicted past it
+    pub budget: usize,
+    /// raw blocks read from devices not held in memory
+    pub blocks: bool,
+    /// parsed inodes
+    pub inodes: bool,
+    /// decoded extent trees and indirect maps
+    pub block_maps: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { budget: DEFAULT_CACHE_BUDGET, blocks: true, inodes: true, block_maps: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheCounts {
+    /// Share of the lookups served from the cache, 0 before the first one
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    pub metadata_blocks: CacheCounts,
+    pub map_nodes: CacheCounts,
+    pub dir_blocks: CacheCounts,
+    pub inodes: CacheCounts,
+    pub block_maps: CacheCounts,
+    /// items dropped to stay within the budget
+    pub evictions: u64,
+    pub entries: usize,
+    /// bytes taken by the cached items, at most `budget`
+    pub used: usize,
+    pub budget: usize,
+}
+
+impl CacheStats {
+    fn counts(&mut self, lookup: Lookup) -> &mut CacheCounts {
+        match lookup {
+            Lookup::Block(BlockKind::Metadata) => &mut self.metadata_blocks,
+            Lookup::Block(BlockKind::MapNode) => &mut self.map_nodes,
+            Lookup::Block(BlockKind::DirBlock) => &mut self.dir_blocks,
+            Lookup::Inode => &mut self.inodes,
+            Lookup::BlockMap => &mut self.block_maps,
+        }
+    }
+}
+
+/// Statistics a lookup is counted in
+#[derive(Debug, Clone, Copy)]
+enum Lookup {
+    Block(BlockKind),
+    Inode,
+    BlockMap,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    /// device block, the same for every file system reading the device
+    Block(u64),
+    /// parsed items depend on the overlay, so they are kept per view
+    Inode { view: u64, i_no: u64 },
+    /// the map follows from the root in `i_block`, which callers may have patched
+    BlockMap { view: u64, i_no: u64, root: [u32; EXT4_N_BLOCKS], extents: bool },
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Block(Arc<[u8]>),
+    Inode(Box<Ext4Inode>),
+    BlockMap(Arc<BlockMap>),
+}
+
+impl Value {
+    fn cost(&self) -> usize {
+        let payload = match self {
+            Self::Block(data) => data.len(),
+            Self::Inode(_) => size_of::<Ext4Inode>(),
+            Self::BlockMap(map) => map.runs.len() * size_of::<BlockRun>(),
+        };
+        // the key is stored twice, in the map and in the recency order
+        payload + 2 * size_of::<Key>() + size_of::<Value>() + size_of::<u64>()
+    }
+}
+
+struct Slot {
+    value: Value,
+    /// last use, orders the slots from least to most recently used
+    tick: u64,
+    cost: usize,
+}
+
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<Key, Slot>,
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key) -> Option<Value> {
+        let slot = self.slots.get_mut(key)?;
+        self.order.remove(&slot.tick);
+        self.tick += 1;
+        slot.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(slot.value.clone())
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        let cost = value.cost();
+        if cost > self.stats.budget {
+            return;
+        }
+        if let Some(old) = self.slots.remove(&key) {
+            self.order.remove(&old.tick);
+            self.stats.used -= old.cost;
+        }
+        while self.stats.used + cost > self.stats.budget {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.slots.remove(&oldest) {
+                self.stats.used -= evicted.cost;
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.slots.insert(key, Slot { value, tick: self.tick, cost });
+        self.stats.used += cost;
+    }
+}
+
+/// Least recently used cache of raw blocks, parsed inodes and decoded block maps
+/// under a single memory budget. It is thread-safe and can be shared by several
+/// file systems through `Ext4Fs::set_cache`, as long as they read the same device.
+pub struct Cache {
+    config: CacheConfig,
+    lru: Mutex<Lru>,
+    next_view: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        let lru = Lru { stats: CacheStats { budget: config.budget, ..Default::default() }, ..Default::default() };
+        Self { config, lru: Mutex::new(lru), next_view: AtomicU64::new(0) }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lock();
+        CacheStats { entries: lru.slots.len(), ..lru.stats.clone() }
+    }
+
+    /// Drops every cached item, the statistics are kept
+    pub fn clear(&self) {
+        let mut lru = self.lock();
+        lru.slots.clear();
+        lru.order.clear();
+        lru.stats.used = 0;
+    }
+
+    /// A poisoned lock only means another thread panicked between two updates of
+    /// plain counters and maps, which are still consistent
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Identifies the file system a parsed item comes from
+    pub(crate) fn new_view(&self) -> u64 {
+        self.next_view.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Looks `key` up and counts the lookup, `read` runs without the lock held so
+    /// other threads are not kept waiting on the device.
+    fn get_or_insert(&self, key: Key, lookup: Lookup, read: impl FnOnce() -> Option<Value>) -> Option<Value> {
+        let cached = {
+            let mut lru = self.lock();
+            let cached = lru.get(&key);
+            let counts = lru.stats.counts(lookup);
+            if cached.is_some() { counts.hits += 1 } else { counts.misses += 1 }
+            cached
+        };
+        if cached.is_some() {
+            return cached;
+        }
+        let value = read()?;
+        self.lock().insert(key, value.clone());
+        Some(value)
+    }
+
+    pub(crate) fn block(&self, block: u64, kind: BlockKind, read: impl FnOnce() -> Option<Vec<u8>>) -> Option<Arc<[u8]>> {
+        if !self.config.blocks {
+            return read().map(Arc::from);
+        }
+        match self.get_or_insert(Key::Block(block), Lookup::Block(kind), || read().map(|data| Value::Block(data.into())))? {
+            Value::Block(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn inode(&self, view: u64, i_no: u64, read: impl FnOnce() -> Option<Ext4Inode>) -> Option<Ext4Inode> {
+        if !self.config.inodes {
+            return read();
+        }
+        let key = Key::Inode { view, i_no };
+        match self.get_or_insert(key, Lookup::Inode, || read().map(|inode| Value::Inode(Box::new(inode))))? {
+            Value::Inode(inode) => Some(*inode),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn block_map(&self, view: u64, inode: &Ext4Inode, extents: bool, build: impl FnOnce() -> BlockMap) -> Arc<BlockMap> {
+        if !self.config.block_maps {
+            return Arc::new(build());
+        }
+        let key = Key::BlockMap { view, i_no: inode.i_no, root: inode.i_block, extents };
+        match self.get_or_insert(key, Lookup::BlockMap, || Some(Value::BlockMap(Arc::new(build())))) {
+            Some(Value::BlockMap(map)) => map,
+            _ => unreachable!("block maps are always built"),
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(CacheConfig::default())
+    }
+}
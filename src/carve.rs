@@ -0,0 +1,299 @@
+use crate::device::{sub_bytes, BlockBytes, BlockDevice};
+use crate::fs_parser::Ext4Fs;
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// A file type the carver recognizes by its first bytes
+pub trait Signature {
+    /// Short name of the type, reported with carved files
+    fn name(&self) -> &str;
+    /// Whether a file of this type starts at `data`, the start of a free block
+    fn matches(&self, data: &[u8]) -> bool;
+    /// Length of the file starting at `data`, `None` when its end is not in `data`
+    fn file_len(&self, data: &[u8]) -> Option<usize>;
+    /// Largest file looked for, `data` is cut to it
+    fn max_len(&self) -> usize;
+}
+
+/// A signature made of a header, and of a footer that ends the file `trailer`
+/// bytes after it. Without a footer files run to `max_len` or the end of the
+/// free range.
+#[derive(Debug, Clone, Copy)]
+pub struct MagicSignature {
+    pub name: &'static str,
+    pub header: &'static [u8],
+    pub footer: Option<&'static [u8]>,
+    pub trailer: usize,
+    pub max_len: usize,
+}
+
+impl Signature for MagicSignature {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(self.header)
+    }
+
+    fn file_len(&self, data: &[u8]) -> Option<usize> {
+        let footer = self.footer?;
+        let body = data.get(self.header.len()..)?;
+        let at = body.windows(footer.len()).position(|window| window == footer)?;
+        let len = self.header.len() + at + footer.len() + self.trailer;
+        (len <= data.len()).then_some(len)
+    }
+
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+}
+
+/// ELF executables and objects, which end with their section header table
+#[derive(Debug, Clone, Copy)]
+pub struct ElfSignature;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_MAX_LEN: usize = 256 << 20;
+
+impl Signature for ElfSignature {
+    fn name(&self) -> &str {
+        "elf"
+    }
+
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+            && data.get(4).is_some_and(|class| (1..=2).contains(class))
+            && data.get(5).is_some_and(|encoding| (1..=2).contains(encoding))
+    }
+
+    /// Section headers come last in files the usual toolchains write, program
+    /// headers are used for files without them
+    fn file_len(&self, data: &[u8]) -> Option<usize> {
+        let is_64 = data[4] == 2;
+        let big_endian = data[5] == 2;
+        let field = |offset: usize, size: usize| -> Option<u64> {
+            let bytes = data.get(offset..offset + size)?;
+            let mut value = [0u8; 8];
+            if big_endian {
+                value[8 - size..].copy_from_slice(bytes);
+                Some(u64::from_be_bytes(value))
+            } else {
+                value[..size].copy_from_slice(bytes);
+                Some(u64::from_le_bytes(value))
+            }
+        };
+        let (word, header_end) = if is_64 { (8, 0x40) } else { (4, 0x34) };
+        let ph_off = field(0x18 + word, word)?;
+        let sh_off = field(0x18 + 2 * word, word)?;
+        let ph_entsize = field(header_end - 10, 2)?;
+        let ph_num = field(header_end - 8, 2)?;
+        let sh_entsize = field(header_end - 6, 2)?;
+        let sh_num = field(header_end - 4, 2)?;
+
+        let mut len = (header_end as u64).max(ph_off.saturating_add(ph_entsize * ph_num));
+        if sh_off != 0 {
+            len = len.max(sh_off.saturating_add(sh_entsize * sh_num));
+        } else {
+            for index in 0..ph_num {
+                let header = ph_off.saturating_add(index * ph_entsize) as usize;
+                let (offset, filesz) = if is_64 {
+                    (field(header + 8, 8)?, field(header + 32, 8)?)
+                } else {
+                    (field(header + 4, 4)?, field(header + 16, 4)?)
+                };
+                len = len.max(offset.saturating_add(filesz));
+            }
+        }
+        (len <= data.len() as u64).then_some(len as usize)
+    }
+
+    fn max_len(&self) -> usize {
+        ELF_MAX_LEN
+    }
+}
+
+/// Gzip members carry no length, the deflate stream would have to be inflated
+#[derive(Debug, Clone, Copy)]
+pub struct GzipSignature;
+
+const GZIP_MAX_LEN: usize = 64 << 20;
+
+impl Signature for GzipSignature {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    /// Deflate method with none of the reserved flags set
+    fn matches(&self, data: &[u8]) -> bool {
+        data.starts_with(b"\x1f\x8b\x08") && data.get(3).is_some_and(|flags| flags & 0xe0 == 0)
+    }
+
+    fn file_len(&self, _data: &[u8]) -> Option<usize> {
+        None
+    }
+
+    fn max_len(&self) -> usize {
+        GZIP_MAX_LEN
+    }
+}
+
+/// JPEG, PNG, PDF, ZIP, ELF and gzip
+pub fn default_signatures() -> Vec<Box<dyn Signature>> {
+    vec![
+        Box::new(MagicSignature {
+            name: "jpeg",
+            header: b"\xff\xd8\xff",
+            footer: Some(b"\xff\xd9"),
+            trailer: 0,
+            max_len: 32 << 20,
+        }),
+        // the footer is the IEND chunk type, followed by its CRC
+        Box::new(MagicSignature {
+            name: "png",
+            header: b"\x89PNG\r\n\x1a\n",
+            footer: Some(b"IEND"),
+            trailer: 4,
+            max_len: 32 << 20,
+        }),
+        // ends at the first `%%EOF`, later incremental updates are cut off
+        Box::new(MagicSignature {
+            name: "pdf",
+            header: b"%PDF-",
+            footer: Some(b"%%EOF"),
+            trailer: 0,
+            max_len: 256 << 20,
+        }),
+        // the end of central directory record, assumed to carry no comment
+        Box::new(MagicSignature {
+            name: "zip",
+            header: b"PK\x03\x04",
+            footer: Some(b"PK\x05\x06"),
+            trailer: 18,
+            max_len: 256 << 20,
+        }),
+        Box::new(ElfSignature),
+        Box::new(GzipSignature),
+    ]
+}
+
+/// A candidate file found in free space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarvedFile<'a> {
+    /// name of the signature that matched
+    pub name: String,
+    /// first block of the file
+    pub block: u64,
+    /// the end of the file was found, otherwise `data` runs to the signature's
+    /// `max_len` or to the end of the free range
+    pub complete: bool,
+    /// borrowed from devices held in memory
+    pub data: Cow<'a, [u8]>,
+}
+
+/// Unallocated block ranges in ascending order, see `Ext4Fs::free_blocks`
+pub struct FreeBlocks<'f, D: BlockDevice> {
+    fs: &'f Ext4Fs<D>,
+    block: u64,
+    end: u64,
+    /// group whose bitmap is loaded, with `None` for a bitmap that cannot be read
+    bitmap: Option<(u64, Option<BlockBytes<'f>>)>,
+}
+
+impl<D: BlockDevice> FreeBlocks<'_, D> {
+    fn is_free(&mut self, block: u64) -> bool {
+        let Some((group, bit)) = self.fs.block_bit(block) else {
+            return false;
+        };
+        if self.bitmap.as_ref().is_none_or(|(loaded, _)| *loaded != group) {
+            self.bitmap = Some((group, self.fs.block_bitmap(group)));
+        }
+        let bitmap = self.bitmap.as_ref().and_then(|(_, bitmap)| bitmap.as_deref());
+        bitmap.and_then(|bitmap| bitmap.get((bit / 8) as usize)).is_some_and(|byte| byte >> (bit % 8) & 1 == 0)
+    }
+}
+
+impl<D: BlockDevice> Iterator for FreeBlocks<'_, D> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = None;
+        while self.block < self.end {
+            match (self.is_free(self.block), start) {
+                (true, None) => start = Some(self.block),
+                (false, Some(start)) => return Some(start..self.block),
+                _ => {}
+            }
+            self.block += 1;
+        }
+        start.map(|start| start..self.end)
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Ranges of blocks the block bitmaps mark free, merged across groups. Groups
+    /// whose bitmap cannot be read count as allocated.
+    pub fn free_blocks(&self) -> FreeBlocks<'_, D> {
+        let super_block = self.super_block();
+        FreeBlocks { fs: self, block: super_block.s_first_data_block as u64, end: super_block.blocks_count(), bitmap: None }
+    }
+
+    /// Looks for files starting at a free block, trying `signatures` in order. A
+    /// file is only carved from contiguous free blocks, as the device holds them.
+    /// The search resumes after a complete file and at the next block otherwise.
+    /// Free blocks are read one at a time until a signature matches, and files are
+    /// read as they are yielded.
+    pub fn carve<'s>(&self, signatures: &'s [Box<dyn Signature>]) -> Carve<'_, 's, D> {
+        Carve { fs: self, signatures, free: self.free_blocks(), offset: 0, end: 0 }
+    }
+}
+
+/// Files found in free space, see `Ext4Fs::carve`
+pub struct Carve<'f, 's, D: BlockDevice> {
+    fs: &'f Ext4Fs<D>,
+    signatures: &'s [Box<dyn Signature>],
+    free: FreeBlocks<'f, D>,
+    /// device offset to look at next and end of its free range
+    offset: u64,
+    end: u64,
+}
+
+impl<'f, D: BlockDevice> Iterator for Carve<'f, '_, D> {
+    type Item = CarvedFile<'f>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_size = self.fs.block_size();
+        let device = self.fs.device();
+        loop {
+            if self.offset >= self.end {
+                let range = self.free.next()?;
+                self.offset = range.start * block_size;
+                self.end = (range.end * block_size).min(device.len());
+                continue;
+            }
+            let offset = self.offset;
+            let head_len = block_size.min(self.end - offset) as usize;
+            let Some(head) = device.read_bytes(offset, head_len) else {
+                self.offset = self.end;
+                continue;
+            };
+            let Some(signature) = self.signatures.iter().find(|signature| signature.matches(&head)) else {
+                self.offset += block_size;
+                continue;
+            };
+            let max_len = (self.end - offset).min(signature.max_len() as u64) as usize;
+            let Some(data) = device.read_bytes(offset, max_len) else {
+                self.offset = self.end;
+                continue;
+            };
+            let len = signature.file_len(&data);
+            self.offset += len.map_or(block_size, |len| (len as u64).next_multiple_of(block_size));
+            return Some(CarvedFile {
+                name: signature.name().to_string(),
+                block: offset / block_size,
+                complete: len.is_some(),
+                data: sub_bytes(data, 0..len.unwrap_or(max_len)).unwrap_or_default(),
+            });
+        }
+    }
+}
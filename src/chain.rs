@@ -1,51 +1,83 @@
+use crate::device::BlockBytes;
 use std::marker::PhantomData;
 
 pub trait ChainItem: Sized {
+    /// Smallest number of bytes `from_bytes` needs to decode an item
+    const MIN_LEN: usize;
     fn from_bytes(input: &[u8]) -> (usize, Self);
 }
 
 impl ChainItem for u8 {
+    const MIN_LEN: usize = 1;
     fn from_bytes(input: &[u8]) -> (usize, Self) {
         (1, input[0])
     }
 }
 
+/// Buffers of a chain, read as the chain gets to them
+type Buffers<'a> = Box<dyn Iterator<Item = BlockBytes<'a>> + Send + Sync + 'a>;
+
 pub struct BufferChainer<'a, T: ChainItem> {
-    buffers: Vec<&'a [u8]>,
-    buffer_index: usize,
+    buffers: Buffers<'a>,
+    current: Option<BlockBytes<'a>>,
     byte_offset: usize,
     _phantom: PhantomData<T>
 }
 
 impl<'a, T: ChainItem> BufferChainer<'a, T> {
-    pub fn new(buffers: Vec<&'a [u8]>) -> Self {
-        println!("buffers len is {}", buffers.len());
-        Self { buffers, buffer_index: 0, byte_offset: 0, _phantom: PhantomData }
+    pub fn new(buffers: Vec<impl Into<BlockBytes<'a>> + Send + Sync + 'a>) -> Self {
+        Self::streaming(buffers.into_iter())
+    }
+
+    /// Chains buffers produced on demand, e.g. blocks read from the device one at
+    /// a time. Chains can be sent to and shared with other threads.
+    pub fn streaming(buffers: impl Iterator<Item = impl Into<BlockBytes<'a>> + 'a> + Send + Sync + 'a) -> Self {
+        Self { buffers: Box::new(buffers.map(Into::into)), current: None, byte_offset: 0, _phantom: PhantomData }
     }
 
-    pub fn read_all(&self) -> Vec<u8> {
-        self.buffers.concat()
+    /// The buffers not consumed yet, starting with the rest of the current one
+    pub fn into_buffers(self) -> impl Iterator<Item = BlockBytes<'a>> + Send + Sync + 'a {
+        let current = self.current.and_then(|buffer| {
+            let len = buffer.len();
+            buffer.slice(self.byte_offset.min(len)..len)
+        });
+        current.into_iter().chain(self.buffers)
+    }
+
+    pub fn read_all(self) -> Vec<u8> {
+        self.into_buffers().fold(Vec::new(), |mut all, buffer| {
+            all.extend_from_slice(&buffer);
+            all
+        })
     }
 }
 
 impl<'a, T: ChainItem> Iterator for BufferChainer<'a, T> {
     type Item = T;
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(buf) = self.buffers.get(self.buffer_index) {
-            println!("{}, {}, {}", buf.len(), self.byte_offset, self.buffer_index);
+        loop {
+            let buf = match &self.current {
+                Some(buf) => buf,
+                None => {
+                    self.current = Some(self.buffers.next()?);
+                    self.byte_offset = 0;
+                    continue;
+                }
+            };
             let remaining = buf.len().saturating_sub(self.byte_offset);
-            if remaining < size_of::<T>() {
-                self.buffer_index += 1;
-                self.byte_offset = 0;
+            if remaining < T::MIN_LEN {
+                self.current = None;
                 continue;
             }
 
             let (offset, value) = T::from_bytes(&buf[self.byte_offset..]);
-
-            self.byte_offset += offset;
-            println!("{}, {}, {}", buf.len(), self.byte_offset, self.buffer_index);
+            if offset == 0 {
+                // a zero-length record would never advance, give up on this buffer
+                self.byte_offset = buf.len();
+            } else {
+                self.byte_offset += offset;
+            }
             return Some(value);
         }
-        None
     }
 }
@@ -0,0 +1,525 @@
+use crate::defs::{
+    BgFlags, Ext4ExtentHeader, Ext4ExtentIdx, Ext4Inode, IncompatFeatures, InodeFlags, RoCompatFeatures,
+    EXT4_GOOD_OLD_INODE_SIZE,
+};
+use crate::block_map::i_block_bytes;
+use crate::cache::BlockKind;
+use crate::device::BlockDevice;
+use crate::fs_parser::Ext4Fs;
+use crate::htree::{DX_ENTRY_SIZE, DX_NODE_ENTRIES_OFFSET, DX_ROOT_INFO_OFFSET};
+use crate::orphan::EXT4_ORPHAN_BLOCK_TAIL_SIZE;
+use crate::xattr::EXT4_XATTR_MAGIC;
+use nom_derive::Parse;
+use std::collections::BTreeSet;
+
+/// Reflected CRC32C (Castagnoli) polynomial
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+/// Reflected CRC16 (ANSI) polynomial used by `GDT_CSUM`
+const CRC16_POLY: u16 = 0xA001;
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+static CRC16_TABLE: [u16; 256] = crc16_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC16_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a CRC32C over `data`. Like the kernel's `ext4_chksum` there is no final
+/// inversion, the first call is seeded with `!0` or a checksum seed.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Continues the CRC16 of the legacy `GDT_CSUM` group descriptor checksums.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc = CRC16_TABLE[((crc ^ byte as u16) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Offset of `s_checksum`, the last field of the super block
+const EXT4_S_CHECKSUM_OFFSET: usize = 0x3FC;
+/// Offset of `bg_checksum` in a group descriptor
+const EXT4_BG_CHECKSUM_OFFSET: usize = 0x1E;
+/// Descriptor sizes needed to hold the high halves of the bitmap checksums
+const EXT4_BG_BLOCK_BITMAP_CSUM_HI_END: usize = 0x3A;
+const EXT4_BG_INODE_BITMAP_CSUM_HI_END: usize = 0x3C;
+/// Offsets of `l_i_checksum_lo` and `i_checksum_hi` in the on-disk inode
+const EXT4_INODE_CSUM_LO_OFFSET: usize = 0x7C;
+const EXT4_INODE_CSUM_HI_OFFSET: usize = 0x82;
+/// Offset of `h_checksum` in the xattr block header
+const EXT4_XATTR_CSUM_OFFSET: usize = 0x10;
+/// Extent header and entries are both 12 bytes
+const EXT4_EXTENT_ENTRY_SIZE: usize = 12;
+/// Size of the fake dirent closing a directory leaf, and its file type marker
+const EXT4_DIR_TAIL_SIZE: usize = 12;
+const EXT4_DIR_TAIL_FT: u8 = 0xDE;
+/// `dt_reserved` in front of the checksum of a dx_tail
+const EXT4_DX_TAIL_RESERVED: usize = 4;
+const EXT4_DX_TAIL_SIZE: usize = 8;
+
+/// Metadata structure a checksum covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsumKind {
+    SuperBlock,
+    GroupDesc { group: u64 },
+    BlockBitmap { group: u64 },
+    InodeBitmap { group: u64 },
+    Inode { i_no: u64 },
+    ExtentBlock { i_no: u64, block: u64 },
+    DirLeaf { i_no: u64, block: u64 },
+    DxNode { i_no: u64, block: u64 },
+    XattrBlock { block: u64 },
+    OrphanBlock { block: u64 },
+}
+
+/// Outcome of one checksum check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsumCheck {
+    pub kind: CsumKind,
+    /// checksum found on disk, `None` when the structure has no room for one
+    pub stored: Option<u32>,
+    pub computed: u32,
+}
+
+impl CsumCheck {
+    pub fn is_ok(&self) -> bool {
+        self.stored == Some(self.computed)
+    }
+}
+
+/// Every checksum checked by a verification pass
+#[derive(Debug, Clone, Default)]
+pub struct CsumReport {
+    pub checks: Vec<CsumCheck>,
+}
+
+impl CsumReport {
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(CsumCheck::is_ok)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CsumCheck> {
+        self.checks.iter().filter(|check| !check.is_ok())
+    }
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    pub fn has_metadata_csum(&self) -> bool {
+        self.super_block().s_feature_ro_compat.contains(RoCompatFeatures::METADATA_CSUM)
+    }
+
+    /// Seed of every metadata checksum but the super block's, precomputed in the
+    /// super block under `CSUM_SEED` so the UUID can change.
+    pub fn csum_seed(&self) -> u32 {
+        let super_block = self.super_block();
+        if super_block.s_feature_incompat.contains(IncompatFeatures::CSUM_SEED) {
+            super_block.s_checksum_seed
+        } else {
+            crc32c(!0, &super_block.s_uuid)
+        }
+    }
+
+    /// Seed of the checksums of an inode and the blocks it owns
+    fn inode_csum_seed(&self, inode: &Ext4Inode) -> u32 {
+        let csum = crc32c(self.csum_seed(), &(inode.i_no as u32).to_le_bytes());
+        crc32c(csum, &inode.i_generation.to_le_bytes())
+    }
+
+    /// Checks every checksummed structure reachable from the super block: group
+    /// descriptors, bitmaps, allocated inodes with their extent tree and directory
+    /// blocks, xattr blocks and the orphan file. Nothing is checked on file systems
+    /// without checksums.
+    pub fn verify_checksums(&self) -> CsumReport {
+        let mut report = CsumReport::default();
+        report.checks.extend(self.verify_super_block_csum());
+        let mut xattr_blocks = BTreeSet::new();
+        for group in 0..self.group_descs.len() as u64 {
+            report.checks.extend(self.verify_group_desc_csum(group));
+            report.checks.extend(self.verify_bitmap_csums(group));
+            if !self.has_metadata_csum() || self.group_descs[group as usize].bg_flags.contains(BgFlags::INODE_UNINIT) {
+                continue;
+            }
+            let inodes_per_group = self.super_block().s_inodes_per_group as u64;
+            for i_no in group * inodes_per_group + 1..=(group + 1) * inodes_per_group {
+                let Some(inode) = self.get_inode(i_no) else {
+                    continue;
+                };
+                report.checks.extend(self.verify_inode_csums(&inode));
+                if inode.file_acl() != 0 {
+                    xattr_blocks.insert(inode.file_acl());
+                }
+            }
+        }
+        for block in xattr_blocks {
+            report.checks.extend(self.verify_xattr_block_csum(block));
+        }
+        for block in self.orphan_file().unwrap_or_default() {
+            report.checks.extend(block.csum);
+        }
+        report
+    }
+
+    pub fn verify_super_block_csum(&self) -> Option<CsumCheck> {
+        if !self.has_metadata_csum() {
+            return None;
+        }
+        let raw = self.super_block_bytes()?;
+        Some(CsumCheck {
+            kind: CsumKind::SuperBlock,
+            stored: Some(self.super_block().s_checksum),
+            computed: crc32c(!0, &raw[..EXT4_S_CHECKSUM_OFFSET]),
+        })
+    }
+
+    /// `metadata_csum` keeps the low 16 bits of a CRC32C in `bg_checksum`, the older
+    /// `GDT_CSUM` a CRC16 seeded with the UUID. Both skip the checksum field itself.
+    /// A group past the last descriptor is reported bad, with no stored checksum.
+    pub fn verify_group_desc_csum(&self, group: u64) -> Option<CsumCheck> {
+        let super_block = self.super_block();
+        let metadata_csum = self.has_metadata_csum();
+        if !metadata_csum && !super_block.s_feature_ro_compat.contains(RoCompatFeatures::GDT_CSUM) {
+            return None;
+        }
+        let kind = CsumKind::GroupDesc { group };
+        let Some(desc) = self.group_descs.get(group as usize) else {
+            return Some(CsumCheck { kind, stored: None, computed: 0 });
+        };
+        let raw = self.group_desc_bytes(group)?;
+        let computed = if metadata_csum {
+            group_desc_crc32c(self.csum_seed(), group, &raw) as u32
+        } else {
+            group_desc_crc16(&super_block.s_uuid, group, &raw) as u32
+        };
+        Some(CsumCheck { kind, stored: Some(desc.bg_checksum as u32), computed })
+    }
+
+    /// Bitmaps of groups flagged uninitialized were never written and are skipped.
+    /// 32-byte descriptors only have room for the low 16 bits of the checksums.
+    pub fn verify_bitmap_csums(&self, group: u64) -> Vec<CsumCheck> {
+        let mut checks = Vec::new();
+        let Some(desc) = self.group_descs.get(group as usize).filter(|_| self.has_metadata_csum()) else {
+            return checks;
+        };
+        let super_block = self.super_block();
+        let desc_size = super_block.desc_size();
+        let bitmaps = [
+            (
+                CsumKind::BlockBitmap { group },
+                BgFlags::BLOCK_UNINIT,
+                desc.block_bitmap(),
+                super_block.s_clusters_per_group as usize / 8,
+                desc.block_bitmap_csum(),
+                desc_size >= EXT4_BG_BLOCK_BITMAP_CSUM_HI_END,
+            ),
+            (
+                CsumKind::InodeBitmap { group },
+                BgFlags::INODE_UNINIT,
+                desc.inode_bitmap(),
+                super_block.s_inodes_per_group as usize / 8,
+                desc.inode_bitmap_csum(),
+                desc_size >= EXT4_BG_INODE_BITMAP_CSUM_HI_END,
+            ),
+        ];
+        for (kind, uninit, block, len, stored, has_hi) in bitmaps {
+            if desc.bg_flags.contains(uninit) {
+                continue;
+            }
+            let bitmap = self.get_block(block);
+            let Some(bitmap) = bitmap.as_deref().and_then(|bitmap| bitmap.get(..len)) else {
+                checks.push(CsumCheck { kind, stored: None, computed: 0 });
+                continue;
+            };
+            let mask = if has_hi { !0 } else { 0xffff };
+            checks.push(CsumCheck { kind, stored: Some(stored & mask), computed: crc32c(self.csum_seed(), bitmap) & mask });
+        }
+        checks
+    }
+
+    /// Checks an inode and the extent tree and directory blocks it owns. Its xattr
+    /// block may be shared and is left to `verify_xattr_block_csum`.
+    pub fn verify_inode_csums(&self, inode: &Ext4Inode) -> Vec<CsumCheck> {
+        let mut checks = Vec::new();
+        if !self.has_metadata_csum() {
+            return checks;
+        }
+        checks.extend(self.verify_inode_csum(inode));
+        if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            return checks;
+        }
+        if inode.i_flags.contains(InodeFlags::EXTENTS) {
+            self.verify_extent_node_csums(inode, &i_block_bytes(inode), None, &mut checks);
+        }
+        if inode.i_mode.ty.is_dir() {
+            self.verify_dir_csums(inode, &mut checks);
+        }
+        checks
+    }
+
+    /// The checksum skips `l_i_checksum_lo` and `i_checksum_hi`. Inodes too small
+    /// for `i_checksum_hi` only store the low 16 bits.
+    fn verify_inode_csum(&self, inode: &Ext4Inode) -> Option<CsumCheck> {
+        let raw = self.get_inode_bytes(inode.i_no)?;
+        let (mut csum, has_hi) = self.inode_csum(inode, &raw);
+        let mut stored = le16(&raw, EXT4_INODE_CSUM_LO_OFFSET) as u32;
+        if has_hi {
+            stored |= (le16(&raw, EXT4_INODE_CSUM_HI_OFFSET) as u32) << 16;
+        } else {
+            csum &= 0xffff;
+        }
+        Some(CsumCheck { kind: CsumKind::Inode { i_no: inode.i_no }, stored: Some(stored), computed: csum })
+    }
+
+    /// Stores the checksum of `raw`, the on-disk bytes of `inode`
+    pub(crate) fn set_inode_csum(&self, inode: &Ext4Inode, raw: &mut [u8]) {
+        let (csum, has_hi) = self.inode_csum(inode, raw);
+        raw[EXT4_INODE_CSUM_LO_OFFSET..EXT4_INODE_CSUM_LO_OFFSET + 2].copy_from_slice(&(csum as u16).to_le_bytes());
+        if has_hi {
+            let hi = ((csum >> 16) as u16).to_le_bytes();
+            raw[EXT4_INODE_CSUM_HI_OFFSET..EXT4_INODE_CSUM_HI_OFFSET + 2].copy_from_slice(&hi);
+        }
+    }
+
+    /// Full 32-bit checksum and whether the inode has room for its high half
+    fn inode_csum(&self, inode: &Ext4Inode, raw: &[u8]) -> (u32, bool) {
+        let mut csum = crc32c(self.inode_csum_seed(inode), &raw[..EXT4_INODE_CSUM_LO_OFFSET]);
+        csum = crc32c(csum, &[0; 2]);
+        csum = crc32c(csum, &raw[EXT4_INODE_CSUM_LO_OFFSET + 2..EXT4_GOOD_OLD_INODE_SIZE]);
+        let has_hi = raw.len() > EXT4_GOOD_OLD_INODE_SIZE && inode.fits_in_inode(EXT4_INODE_CSUM_HI_OFFSET, 2);
+        if raw.len() > EXT4_GOOD_OLD_INODE_SIZE {
+            let mut offset = EXT4_INODE_CSUM_HI_OFFSET;
+            csum = crc32c(csum, &raw[EXT4_GOOD_OLD_INODE_SIZE..offset]);
+            if has_hi {
+                csum = crc32c(csum, &[0; 2]);
+                offset += 2;
+            }
+            csum = crc32c(csum, &raw[offset..]);
+        }
+        (csum, has_hi)
+    }
+
+    /// Walks the extent tree, checking the tail that follows `eh_max` entries of
+    /// every node stored in a block. The root in `i_block` has no tail.
+    fn verify_extent_node_csums(&self, inode: &Ext4Inode, node: &[u8], block: Option<u64>, checks: &mut Vec<CsumCheck>) {
+        let Ok((mut rest, header)) = Ext4ExtentHeader::parse(node) else {
+            return;
+        };
+        if !header.is_header() {
+            return;
+        }
+        if let Some(block) = block {
+            let tail = EXT4_EXTENT_ENTRY_SIZE * (1 + header.eh_max as usize);
+            let kind = CsumKind::ExtentBlock { i_no: inode.i_no, block };
+            let computed = crc32c(self.inode_csum_seed(inode), &node[..tail.min(node.len())]);
+            let stored = node.get(tail..tail + 4).map(|raw| le32(raw, 0));
+            checks.push(CsumCheck { kind, stored, computed });
+        }
+        if header.eh_depth == 0 {
+            return;
+        }
+        for _ in 0..header.eh_entries {
+            let Ok((next, index)) = Ext4ExtentIdx::parse(rest) else {
+                return;
+            };
+            rest = next;
+            if let Some(child) = self.get_block_as(index.ei_leaf(), BlockKind::MapNode) {
+                self.verify_extent_node_csums(inode, &child, Some(index.ei_leaf()), checks);
+            }
+        }
+    }
+
+    /// Leaves end with a fake dirent holding the checksum. In indexed directories
+    /// the first block and the interior nodes carry a dx_tail after `limit` entries.
+    fn verify_dir_csums(&self, dir: &Ext4Inode, checks: &mut Vec<CsumCheck>) {
+        let block_size = self.block_size() as usize;
+        let seed = self.inode_csum_seed(dir);
+        let indexed = dir.i_flags.contains(InodeFlags::INDEX);
+        let blocks = dir.size().div_ceil(block_size as u64);
+        for run in dir.block_map(self).runs {
+            for logical in run.logical..(run.logical + run.len).min(blocks) {
+                let block = run.physical + logical - run.logical;
+                let Some(data) = self.get_block_as(block, BlockKind::DirBlock) else {
+                    continue;
+                };
+                let count_offset = if indexed && logical == 0 {
+                    Some(DX_ROOT_INFO_OFFSET + data[DX_ROOT_INFO_OFFSET + 5] as usize)
+                } else if indexed && le32(&data, 0) == 0 && rec_len(le16(&data, 4), block_size) == block_size {
+                    Some(DX_NODE_ENTRIES_OFFSET)
+                } else {
+                    None
+                };
+                let check = match count_offset {
+                    Some(count_offset) => {
+                        let kind = CsumKind::DxNode { i_no: dir.i_no, block };
+                        dx_csum(&data, count_offset, seed, kind)
+                    }
+                    None => {
+                        let kind = CsumKind::DirLeaf { i_no: dir.i_no, block };
+                        let (entries, tail) = data.split_at(block_size - EXT4_DIR_TAIL_SIZE);
+                        let is_tail = le32(tail, 0) == 0
+                            && le16(tail, 4) as usize == EXT4_DIR_TAIL_SIZE
+                            && tail[6] == 0
+                            && tail[7] == EXT4_DIR_TAIL_FT;
+                        CsumCheck { kind, stored: is_tail.then(|| le32(tail, 8)), computed: crc32c(seed, entries) }
+                    }
+                };
+                checks.push(check);
+            }
+        }
+    }
+
+    /// The checksum covers the block number and the whole block with `h_checksum` zeroed.
+    pub fn verify_xattr_block_csum(&self, block: u64) -> Option<CsumCheck> {
+        if !self.has_metadata_csum() {
+            return None;
+        }
+        let data = self.get_block(block)?;
+        if le32(&data, 0) != EXT4_XATTR_MAGIC {
+            return Some(CsumCheck { kind: CsumKind::XattrBlock { block }, stored: None, computed: 0 });
+        }
+        let mut csum = crc32c(self.csum_seed(), &block.to_le_bytes());
+        csum = crc32c(csum, &data[..EXT4_XATTR_CSUM_OFFSET]);
+        csum = crc32c(csum, &[0; 4]);
+        csum = crc32c(csum, &data[EXT4_XATTR_CSUM_OFFSET + 4..]);
+        Some(CsumCheck {
+            kind: CsumKind::XattrBlock { block },
+            stored: Some(le32(&data, EXT4_XATTR_CSUM_OFFSET)),
+            computed: csum,
+        })
+    }
+
+    /// Orphan file blocks are checksummed with the seed of the orphan file inode,
+    /// over their block number and entries
+    pub(crate) fn orphan_block_csum(&self, orphan_file: &Ext4Inode, block: u64, data: &[u8]) -> Option<CsumCheck> {
+        if !self.has_metadata_csum() {
+            return None;
+        }
+        let entries_size = data.len() - EXT4_ORPHAN_BLOCK_TAIL_SIZE;
+        let csum = crc32c(self.inode_csum_seed(orphan_file), &block.to_le_bytes());
+        Some(CsumCheck {
+            kind: CsumKind::OrphanBlock { block },
+            stored: Some(le32(data, entries_size + 4)),
+            computed: crc32c(csum, &data[..entries_size]),
+        })
+    }
+}
+
+/// `bg_checksum` of the raw descriptor of `group` under `metadata_csum`
+fn group_desc_crc32c(seed: u32, group: u64, raw: &[u8]) -> u16 {
+    let csum = crc32c(seed, &(group as u32).to_le_bytes());
+    let csum = crc32c(crc32c(csum, &raw[..EXT4_BG_CHECKSUM_OFFSET]), &[0; 2]);
+    crc32c(csum, &raw[EXT4_BG_CHECKSUM_OFFSET + 2..]) as u16
+}
+
+/// `bg_checksum` of the raw descriptor of `group` under `GDT_CSUM`
+fn group_desc_crc16(uuid: &[u8; 16], group: u64, raw: &[u8]) -> u16 {
+    let crc = crc16(crc16(!0, uuid), &(group as u32).to_le_bytes());
+    crc16(crc16(crc, &raw[..EXT4_BG_CHECKSUM_OFFSET]), &raw[EXT4_BG_CHECKSUM_OFFSET + 2..])
+}
+
+/// 64K blocks store a full-block `rec_len` as 0 or 65535
+pub(crate) fn rec_len(len: u16, block_size: usize) -> usize {
+    if block_size >= 65536 && (len == 0 || len == u16::MAX) { block_size } else { len as usize }
+}
+
+/// Checksums the count/limit header and the `count` entries in use, then the
+/// dx_tail found after `limit` entries with its checksum zeroed.
+fn dx_csum(data: &[u8], count_offset: usize, seed: u32, kind: CsumKind) -> CsumCheck {
+    let Some(limit_count) = data.get(count_offset..count_offset + 4) else {
+        return CsumCheck { kind, stored: None, computed: 0 };
+    };
+    let (limit, count) = (le16(limit_count, 0) as usize, le16(limit_count, 2) as usize);
+    let tail = count_offset + limit * DX_ENTRY_SIZE;
+    if tail + EXT4_DX_TAIL_SIZE > data.len() || count > limit {
+        return CsumCheck { kind, stored: None, computed: 0 };
+    }
+    let csum = crc32c(seed, &data[..count_offset + count * DX_ENTRY_SIZE]);
+    let csum = crc32c(csum, &data[tail..tail + EXT4_DX_TAIL_RESERVED]);
+    let computed = crc32c(csum, &[0; 4]);
+    CsumCheck { kind, stored: Some(le32(data, tail + EXT4_DX_TAIL_RESERVED)), computed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// UUID `0b7a6e0e-6f3d-4b5a-9e4c-2f1d8c7b6a59`
+    const UUID: [u8; 16] = [
+        0x0b, 0x7a, 0x6e, 0x0e, 0x6f, 0x3d, 0x4b, 0x5a, 0x9e, 0x4c, 0x2f, 0x1d, 0x8c, 0x7b, 0x6a, 0x59,
+    ];
+
+    #[test]
+    fn check_values() {
+        // CRC-32C and CRC-16/MODBUS of "123456789", crc32c without the final inversion
+        assert_eq!(!crc32c(!0, b"123456789"), 0xe306_9283);
+        assert_eq!(crc16(!0, b"123456789"), 0x4b37);
+    }
+
+    #[test]
+    fn uuid_csum_seed() {
+        // dumpe2fs "Checksum seed" after tune2fs -O metadata_csum_seed
+        assert_eq!(crc32c(!0, &UUID), 0x0071_a74d);
+    }
+
+    #[test]
+    fn metadata_csum_group_desc() {
+        // group 1 of a 1k-block 64bit file system, dumpe2fs reports csum 0x251f
+        let raw = [
+            0xa3, 0x00, 0x00, 0x00, 0xa6, 0x00, 0x00, 0x00, 0x52, 0x02, 0x00, 0x00, 0x5f, 0x1b, 0xa8, 0x06, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x63, 0x23, 0x00, 0x00, 0xa8, 0x06, 0x1f, 0x25, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xf7, 0x82, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(group_desc_crc32c(crc32c(!0, &UUID), 1, &raw), 0x251f);
+    }
+
+    #[test]
+    fn gdt_csum_group_desc() {
+        // group 1 of a 1k-block uninit_bg file system, dumpe2fs reports csum 0xd92e
+        let raw = [
+            0x53, 0x00, 0x00, 0x00, 0x56, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0xaf, 0x1b, 0xa8, 0x06, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa8, 0x06, 0x2e, 0xd9,
+        ];
+        assert_eq!(group_desc_crc16(&UUID, 1, &raw), 0xd92e);
+    }
+}
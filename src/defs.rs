@@ -1,11 +1,16 @@
 use bitflags::bitflags;
 use nom::combinator::map;
-use nom::number::complete::{le_u8, le_u16, le_u32};
+use nom::number::complete::{be_u32, le_u8, le_u16, le_u32};
 use nom_derive::{nom, Nom, Parse};
+use std::borrow::Cow;
 use std::string::FromUtf8Error;
 use std::{mem::offset_of, ptr::slice_from_raw_parts};
 
+use crate::block_map::{extent_runs, indirect_runs, BlockMap, ZERO_BLOCK};
+use crate::cache::BlockKind;
 use crate::chain::{BufferChainer, ChainItem};
+use crate::device::{BlockBytes, BlockDevice};
+use crate::fs_parser::Ext4Fs;
 
 pub const EXT4_LABEL_MAX: usize = 16;
 pub const EXT4_S_ERR_END: usize = offset_of!(Ext4SuperBlock, s_mount_opts);
@@ -96,7 +101,8 @@ pub struct Ext4SuperBlock {
     pub s_mmp_update_interval: u16,
     pub s_mmp_block: u64,
     pub s_raid_stripe_width: u32,
-    #[nom(Map = "|x: u8| (2 << (x + 9)) as u64", Parse = "le_u8")]
+    /// groups per flex group, decoded from its log like `s_log_block_size`
+    #[nom(Map = "|x: u8| 1u64 << x.min(63)", Parse = "le_u8")]
     pub s_log_groups_per_flex: u64,
     pub s_checksum_type: u8,
     pub s_encryption_level: u8,
@@ -146,10 +152,66 @@ pub struct Ext4SuperBlock {
     pub s_checksum: u32,
 }
 
-const EXT4_NDIR_BLOCKS: usize = 12;
-const EXT4_IND_BLOCK: usize = EXT4_NDIR_BLOCKS;
-const EXT4_DIND_BLOCK: usize = EXT4_IND_BLOCK + 1;
-const EXT4_TIND_BLOCK: usize = EXT4_DIND_BLOCK + 1;
+pub const EXT4_SUPER_MAGIC: u16 = 0xEF53;
+/// Size of a group descriptor without the 64bit feature
+pub const EXT4_MIN_DESC_SIZE: usize = 32;
+/// Size of a group descriptor with the 64bit feature
+pub const EXT4_MIN_DESC_SIZE_64BIT: usize = 64;
+/// Largest `s_desc_size`, the smallest block size
+pub const EXT4_MAX_DESC_SIZE: usize = 1024;
+
+impl Ext4SuperBlock {
+    pub fn is_64bit(&self) -> bool {
+        self.s_feature_incompat.contains(IncompatFeatures::_64BIT)
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        if self.is_64bit() {
+            ((self.s_blocks_count_hi as u64) << 32) | self.s_blocks_count_lo as u64
+        } else {
+            self.s_blocks_count_lo as u64
+        }
+    }
+
+    /// On-disk size of one group descriptor
+    pub fn desc_size(&self) -> usize {
+        if self.is_64bit() {
+            (self.s_desc_size as usize).max(EXT4_MIN_DESC_SIZE_64BIT)
+        } else {
+            EXT4_MIN_DESC_SIZE
+        }
+    }
+
+    /// Blocks per cluster, the unit block bitmaps count in, 1 without bigalloc
+    pub fn cluster_ratio(&self) -> u64 {
+        if self.s_feature_ro_compat.contains(RoCompatFeatures::BIGALLOC) {
+            (self.s_log_cluster_size / self.s_log_block_size).max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Number of block groups, the last one may be partial
+    pub fn group_count(&self) -> u64 {
+        let blocks = self.blocks_count().saturating_sub(self.s_first_data_block as u64);
+        blocks.div_ceil(self.s_blocks_per_group as u64)
+    }
+
+    /// Groups whose bitmaps and inode tables are packed together, 1 without the
+    /// flex_bg feature
+    pub fn groups_per_flex(&self) -> u64 {
+        if self.s_feature_incompat.contains(IncompatFeatures::FLEX_BG) {
+            self.s_log_groups_per_flex
+        } else {
+            1
+        }
+    }
+}
+
+pub const EXT4_NDIR_BLOCKS: usize = 12;
+pub const EXT4_IND_BLOCK: usize = EXT4_NDIR_BLOCKS;
+pub const EXT4_DIND_BLOCK: usize = EXT4_IND_BLOCK + 1;
+pub const EXT4_TIND_BLOCK: usize = EXT4_DIND_BLOCK + 1;
 /// Number of block pointers in the inode
 pub const EXT4_N_BLOCKS: usize = EXT4_TIND_BLOCK + 1; // adjust if different
 
@@ -186,72 +248,122 @@ pub struct Ext4Inode {
     pub i_crtime_extra: u32, // Creation time extra
     pub i_version_hi: u32,  // High 32 bits for 64-bit version
     pub i_projid: u32,      // Project ID
+    #[nom(Ignore)]
+    pub i_no: u64,          // Inode number, not stored on disk
 }
 
-fn read_extent_node(input: &[u8]) -> &Ext4ExtentHeader {
-    unsafe { &*((input as *const [u8]) as *const u32 as *const Ext4ExtentHeader)}
-}
+/// Byte offset of `i_block` inside the on-disk inode
+pub const EXT4_I_BLOCK_OFFSET: usize = 0x28;
+/// Inline data kept in `i_block` before spilling into the `system.data` xattr
+pub const EXT4_MIN_INLINE_DATA_SIZE: usize = EXT4_N_BLOCKS * 4;
+/// Size of the parent inode number heading an inline directory
+pub const EXT4_INLINE_DOTDOT_SIZE: usize = 4;
+/// Size of the inode fields every revision has, `i_extra_isize` counts from here
+pub const EXT4_GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Size of the fields `Ext4Inode` knows about, including the extra ones
+pub const EXT4_INODE_PARSED_SIZE: usize = 160;
 
 impl Ext4Inode {
+    /// Parses an on-disk inode of `input.len()` bytes. Fields past the end, like the
+    /// extra fields of a 128-byte inode, are left zeroed.
+    pub fn parse_sized(input: &[u8]) -> Option<Self> {
+        let mut buf = [0u8; EXT4_INODE_PARSED_SIZE];
+        let len = input.len().min(buf.len());
+        buf[..len].copy_from_slice(&input[..len]);
+        let (_, mut inode) = Self::parse(&buf).ok()?;
+        if input.len() <= EXT4_GOOD_OLD_INODE_SIZE {
+            inode.i_extra_isize = 0;
+        }
+        Some(inode)
+    }
+
+    /// Whether the extra field at byte `offset` of `size` bytes is covered by `i_extra_isize`
+    pub fn fits_in_inode(&self, offset: usize, size: usize) -> bool {
+        offset + size <= EXT4_GOOD_OLD_INODE_SIZE + self.i_extra_isize as usize
+    }
+
     pub fn get_extend_header(&self) -> &Ext4ExtentHeader {
         unsafe { &*((&self.i_block as *const u32) as *const Ext4ExtentHeader) }
     }
 
-    pub fn read_block<'a, 'b, T: ChainItem>(&'a self, input: &'b [u8], block_size: usize, ) -> BufferChainer<'b, T> {
-        // TODO: use logical block index
-        let header = self.get_extend_header();
-        println!("heaer is {:?}", header);
-        let buffers = if header.eh_depth == 0 {
-            let extents = unsafe {
-                &*slice_from_raw_parts((&self.i_block as *const u32) as *const Ext4Extent, 5)
-            };
-            Self::read_extent(input, &extents[1..(1 + header.eh_entries as usize)], block_size)
+    /// Block holding the external extended attributes, 0 if there is none
+    pub fn file_acl(&self) -> u64 {
+        ((self.osd2.l_i_file_acl_high as u64) << 32) | self.i_file_acl_lo as u64
+    }
+
+    /// Whether a symlink keeps its target in `i_block` rather than in a data block.
+    /// Follows the kernel: a fast symlink owns no blocks besides its xattr block.
+    pub fn is_fast_symlink(&self, cluster_size: u64) -> bool {
+        if !self.i_mode.ty.is_symlink() || self.i_flags.contains(InodeFlags::INLINE_DATA) {
+            return false;
+        }
+        let size = self.size();
+        if self.i_flags.contains(InodeFlags::EA_INODE) {
+            return size != 0 && size < EXT4_MIN_INLINE_DATA_SIZE as u64;
+        }
+        let i_blocks = ((self.osd2.l_i_blocks_high as u64) << 32) | self.i_blocks_lo as u64;
+        let ea_blocks = if self.file_acl() != 0 { cluster_size >> 9 } else { 0 };
+        i_blocks == ea_blocks && size < EXT4_MIN_INLINE_DATA_SIZE as u64
+    }
+
+    /// Device number of a character or block device. The old 16-bit encoding lives
+    /// in `i_block[0]`, the new 32-bit one in `i_block[1]` with `i_block[0]` zeroed.
+    pub fn rdev(&self) -> Option<DeviceNumber> {
+        if !self.i_mode.ty.is_device() {
+            return None;
+        }
+        let rdev = if self.i_block[0] != 0 {
+            let dev = self.i_block[0];
+            DeviceNumber { major: (dev >> 8) & 0xff, minor: dev & 0xff }
         } else {
-            let indices = unsafe {
-                &*slice_from_raw_parts((&self.i_block as *const u32) as *const Ext4ExtentIdx, 5)
-            };
-            Self::read_e_index(input, &indices[1..(1 + header.eh_entries as usize)], block_size)
+            let dev = self.i_block[1];
+            DeviceNumber { major: (dev & 0xfff00) >> 8, minor: (dev & 0xff) | ((dev >> 12) & 0xfff00) }
         };
-        BufferChainer::new(buffers)
+        Some(rdev)
     }
 
-    fn read_extent<'a, 'b>(input: &'b [u8], extents: &'a [Ext4Extent], block_size: usize) -> Vec<&'b [u8]> {
-        let mut ret = Vec::new();
-        for extent in extents {
-            let start = block_size * (extent.ee_start() as usize);
-            let end = start + ((extent.ee_len as usize) * block_size);
-            ret.push(&input[start..end])
-        }
-        ret
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        ((self.i_size_high as u64) << 32) | self.i_size_lo as u64
     }
 
-    fn read_e_index<'a, 'b>(input: &'b [u8], indices: &'a [Ext4ExtentIdx], block_size: usize) -> Vec<&'b [u8]> {
-        let mut ret = Vec::new();
-        for index in indices {
-            let start = index.ei_leaf() as usize * block_size;
-            let header = read_extent_node(&input[start..]);
-            println!("{:?}", header);
-            if header.eh_entries == 0 {
-                break;
-            }
-            let rest_start = start + size_of::<Ext4ExtentHeader>();
-            let rest = &input[rest_start .. rest_start + size_of::<Ext4ExtentHeader>() * header.eh_entries as usize];
-            println!("rest len is {}", rest.len());
-
-            let mut res = if header.eh_depth == 0 {
-                let extents = unsafe {
-                    &*(slice_from_raw_parts(rest.as_ptr() as *const Ext4Extent, header.eh_entries as usize))
-                };
-                Self::read_extent(input, extents, block_size)
+    /// Extent tree or legacy indirect map, depending on the `EXTENTS` flag, with
+    /// the ranges replayed from fast commits applied
+    pub fn block_map<D: BlockDevice>(&self, fs: &Ext4Fs<D>) -> BlockMap {
+        fs.cached_block_map(self, || {
+            let mut block_map = if self.i_flags.contains(InodeFlags::EXTENTS) {
+                BlockMap::new(extent_runs(self, fs))
             } else {
-                let extents = unsafe {
-                    &*(slice_from_raw_parts(rest.as_ptr() as *const Ext4ExtentIdx, header.eh_entries as usize))
-                };
-                Self::read_e_index(input, extents, block_size)
+                BlockMap::new(indirect_runs(self, fs))
             };
-            ret.append(&mut res);
-        }
-        ret
+            for edit in fs.overlay().map_or(&[][..], |overlay| overlay.range_edits(self.i_no)) {
+                edit.apply(&mut block_map);
+            }
+            block_map
+        })
+    }
+
+    /// Chains the inode's blocks in logical order up to `size()`, holes and
+    /// unwritten extents are returned as zeros. Blocks are read as the chain
+    /// reaches them, the chain ends early at a block that cannot be read.
+    pub fn read_block<'f, T: ChainItem, D: BlockDevice + 'f>(&self, fs: &'f Ext4Fs<D>) -> BufferChainer<'f, T> {
+        let block_size = fs.block_size();
+        let block_map = self.block_map(fs);
+        let size = self.size();
+        let is_dir = self.i_mode.ty.is_dir();
+        let buffers = (0..size.div_ceil(block_size)).map_while(move |logical| {
+            let len = (size - logical * block_size).min(block_size) as usize;
+            match block_map.find(logical) {
+                Some(run) if !run.uninit => {
+                    let physical = run.physical + logical - run.logical;
+                    let block = if is_dir { fs.get_block_as(physical, BlockKind::DirBlock) } else { fs.get_data_block(physical).map(BlockBytes::from) }?;
+                    block.slice(0..len)
+                }
+                _ => Some(BlockBytes::Borrowed(&ZERO_BLOCK[..len])),
+            }
+        });
+        BufferChainer::streaming(buffers)
     }
 
     pub fn get_extents(&self) -> Option<&[Ext4Extent]> {
@@ -284,22 +396,19 @@ impl Ext4Inode {
         }
     }
 
-    pub fn get_i_block_contents<'a, 'b>(&'a self, input: &'b [u8], block_size: usize) -> Option<BlockContents<'b>> {
-        // only support for Dir, Regular and Symlink.
-        if self.i_mode.ty.is_symlink() {
-            let bytes: &[u8] = unsafe {
-                std::slice::from_raw_parts(
-                self.i_block.as_ptr() as *const u8,
-                &self.i_block.len() * size_of::<u32>(),
-                )
-            };
-            Some(BlockContents::InliedData(bytes))
-        } else if self.i_mode.ty.is_regular() {
-            let buffer_chainer = self.read_block(input, block_size);
+    /// Data of regular files and symlinks, entries of directories and the device
+    /// number of special files, `None` for an unknown file type
+    pub fn get_i_block_contents<'f, D: BlockDevice>(&self, fs: &'f Ext4Fs<D>) -> Option<BlockContents<'f>> {
+        if self.is_fast_symlink(fs.super_block().s_log_cluster_size) {
+            fs.fast_symlink_contents(self)
+        } else if self.i_mode.ty.is_regular() || self.i_mode.ty.is_symlink() {
+            let buffer_chainer = self.read_block(fs);
             Some(BlockContents::Data(buffer_chainer))
         } else if self.i_mode.ty.is_dir() {
-            let buffer_chainer = self.read_block(input, block_size);
+            let buffer_chainer = self.read_block(fs);
             Some(BlockContents::Dentries(buffer_chainer))
+        } else if self.i_mode.ty.is_special() {
+            Some(BlockContents::Special(self.rdev()))
         } else {
             None
         }
@@ -307,17 +416,28 @@ impl Ext4Inode {
 }
 
 impl ChainItem for Ext4DirEntry {
+    const MIN_LEN: usize = 8;
     fn from_bytes(input: &[u8]) -> (usize, Self) {
         let (input, mut d_entry) = Ext4DirEntry::parse(input).unwrap();
-        d_entry.name.0[..d_entry.name_len as usize].copy_from_slice(&input[..d_entry.name_len as usize]);
+        let name_len = (d_entry.name_len as usize).min(input.len());
+        d_entry.name_len = name_len as u8;
+        d_entry.name.0[..name_len].copy_from_slice(&input[..name_len]);
         (d_entry.rec_len as usize, d_entry)
     }
 }
 
 pub enum BlockContents<'a> {
-    InliedData(&'a [u8]),
+    InliedData(Cow<'a, [u8]>),
     Dentries(BufferChainer<'a, Ext4DirEntry>),
-    Data(BufferChainer<'a, u8>)
+    Data(BufferChainer<'a, u8>),
+    /// Devices, FIFOs and sockets own no data, only devices carry a number
+    Special(Option<DeviceNumber>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
 }
 
 pub enum IBlockRest<'a> {
@@ -377,6 +497,15 @@ impl FileType {
     pub fn is_dir(&self) -> bool {
         *self == Self::Dir
     }
+
+    pub fn is_device(&self) -> bool {
+        matches!(self, Self::CharDev | Self::BlockDev)
+    }
+
+    /// Devices, FIFOs and sockets
+    pub fn is_special(&self) -> bool {
+        matches!(self, Self::CharDev | Self::BlockDev | Self::Fifo | Self::Socket)
+    }
 }
 
 #[repr(C)]
@@ -487,7 +616,58 @@ pub struct Ext4GroupDesc {
     pub bg_reserved: u32,
 }
 
-const EXT4_NAME_LEN: usize = 255;
+impl Ext4GroupDesc {
+    /// Parses a descriptor of `input.len()` bytes, 32-byte descriptors leave the
+    /// `_hi` halves zeroed.
+    pub fn parse_sized(input: &[u8]) -> Option<Self> {
+        let mut buf = [0u8; EXT4_MIN_DESC_SIZE_64BIT];
+        let len = input.len().min(buf.len());
+        buf[..len].copy_from_slice(&input[..len]);
+        Self::parse(&buf).ok().map(|(_, desc)| desc)
+    }
+
+    pub fn block_bitmap(&self) -> u64 {
+        ((self.bg_block_bitmap_hi as u64) << 32) | self.bg_block_bitmap_lo as u64
+    }
+
+    pub fn inode_bitmap(&self) -> u64 {
+        ((self.bg_inode_bitmap_hi as u64) << 32) | self.bg_inode_bitmap_lo as u64
+    }
+
+    pub fn inode_table(&self) -> u64 {
+        ((self.bg_inode_table_hi as u64) << 32) | self.bg_inode_table_lo as u64
+    }
+
+    pub fn exclude_bitmap(&self) -> u64 {
+        ((self.bg_exclude_bitmap_hi as u64) << 32) | self.bg_exclude_bitmap_lo as u64
+    }
+
+    pub fn free_blocks_count(&self) -> u32 {
+        ((self.bg_free_blocks_count_hi as u32) << 16) | self.bg_free_blocks_count_lo as u32
+    }
+
+    pub fn free_inodes_count(&self) -> u32 {
+        ((self.bg_free_inodes_count_hi as u32) << 16) | self.bg_free_inodes_count_lo as u32
+    }
+
+    pub fn used_dirs_count(&self) -> u32 {
+        ((self.bg_used_dirs_count_hi as u32) << 16) | self.bg_used_dirs_count_lo as u32
+    }
+
+    pub fn itable_unused(&self) -> u32 {
+        ((self.bg_itable_unused_hi as u32) << 16) | self.bg_itable_unused_lo as u32
+    }
+
+    pub fn block_bitmap_csum(&self) -> u32 {
+        ((self.bg_block_bitmap_csum_hi as u32) << 16) | self.bg_block_bitmap_csum_lo as u32
+    }
+
+    pub fn inode_bitmap_csum(&self) -> u32 {
+        ((self.bg_inode_bitmap_csum_hi as u32) << 16) | self.bg_inode_bitmap_csum_lo as u32
+    }
+}
+
+pub const EXT4_NAME_LEN: usize = 255;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Name(pub [u8; EXT4_NAME_LEN]);
@@ -518,9 +698,47 @@ pub struct Ext4DirEntry {
 
 impl Ext4DirEntry {
     pub fn get_name(&self) -> Result<String, FromUtf8Error> {
-        let name_end = self.name_len as usize;
-        String::from_utf8(self.name.0[..name_end].to_vec())
+        String::from_utf8(self.name_bytes().to_vec())
     }
+
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name.0[..self.name_len as usize]
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4DxRootInfo {
+    /// always zero
+    pub reserved_zero: u32,
+    /// hash type, one of the `DX_HASH_*` values
+    pub hash_version: u8,
+    /// length of this structure, 8
+    pub info_length: u8,
+    /// depth of the tree below the root
+    pub indirect_levels: u8,
+    pub unused_flags: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4DxCountLimit {
+    /// maximum number of entries that fit in the node
+    pub limit: u16,
+    /// number of entries in use, including this header
+    pub count: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4DxEntry {
+    /// lowest hash found in the block, the low bit marks a hash collision continuation
+    pub hash: u32,
+    /// logical block of the child node or leaf
+    pub block: u32,
 }
 
 #[repr(C)]
@@ -539,9 +757,12 @@ pub struct Ext4ExtentHeader {
     pub eh_generation: u32,
 }
 
+/// Magic number of an extent tree node header
+pub const EXT4_EXT_MAGIC: u16 = 0xf30a;
+
 impl Ext4ExtentHeader {
     pub fn is_header(&self) -> bool {
-        self.eh_magic == 0xf30a
+        self.eh_magic == EXT4_EXT_MAGIC
     }
 }
 
@@ -579,10 +800,65 @@ pub struct Ext4Extent {
     pub ee_start_lo: u32,
 }
 
+/// Extents longer than this are unwritten, their length is `ee_len - EXT_INIT_MAX_LEN`
+pub const EXT_INIT_MAX_LEN: u16 = 1 << 15;
+
 impl Ext4Extent {
     pub fn ee_start(&self) -> u64 {
         ((self.ee_start_hi as u64) << 32) | self.ee_start_lo as u64
     }
+
+    /// Number of blocks covered, with the unwritten marker stripped
+    pub fn len(&self) -> u16 {
+        if self.is_uninit() {
+            self.ee_len - EXT_INIT_MAX_LEN
+        } else {
+            self.ee_len
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_uninit(&self) -> bool {
+        self.ee_len > EXT_INIT_MAX_LEN
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4XattrHeader {
+    /// magic number for identification, 0xEA020000
+    pub h_magic: u32,
+    /// reference count
+    pub h_refcount: u32,
+    /// number of disk blocks used, always 1
+    pub h_blocks: u32,
+    /// hash value of all attributes
+    pub h_hash: u32,
+    /// crc32c(uuid+blknum+xattrblock)
+    pub h_checksum: u32,
+    pub h_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4XattrEntry {
+    /// length of name
+    pub e_name_len: u8,
+    /// attribute name index
+    pub e_name_index: u8,
+    /// offset of the value, relative to the first entry or the block start
+    pub e_value_offs: u16,
+    /// inode in which the value is stored (EA_INODE)
+    pub e_value_inum: u32,
+    /// size of attribute value
+    pub e_value_size: u32,
+    /// hash value of name and value
+    pub e_hash: u32,
 }
 
 #[repr(C)]
@@ -593,6 +869,164 @@ pub struct Ext4ExtentTail {
     pub et_checksum: u32,
 }
 
+/// Magic number heading every jbd2 metadata block
+pub const JBD2_MAGIC_NUMBER: u32 = 0xC03B_3998;
+
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+pub const JBD2_FC_BLOCK: u32 = 6;
+
+/// The journal is big-endian, unlike the rest of the file system
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(BigEndian)]
+pub struct JournalHeader {
+    /// always `JBD2_MAGIC_NUMBER`
+    pub h_magic: u32,
+    /// one of the `JBD2_*_BLOCK` types
+    pub h_blocktype: u32,
+    /// transaction this block belongs to
+    pub h_sequence: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(BigEndian)]
+pub struct JournalSuperBlock {
+    pub s_header: JournalHeader,
+    // 0x0C
+    /// journal device block size
+    pub s_blocksize: u32,
+    /// total blocks in the journal
+    pub s_maxlen: u32,
+    /// first block of log information
+    pub s_first: u32,
+    // 0x18
+    /// first commit ID expected in the log
+    pub s_sequence: u32,
+    /// block number of the start of the log, 0 when the journal is clean
+    pub s_start: u32,
+    /// error value set by jbd2_journal_abort
+    pub s_errno: u32,
+    // 0x24, v2 only
+    #[nom(Parse = "map(be_u32, JournalCompatFeatures::from_bits_truncate)")]
+    pub s_feature_compat: JournalCompatFeatures,
+    #[nom(Parse = "map(be_u32, JournalIncompatFeatures::from_bits_truncate)")]
+    pub s_feature_incompat: JournalIncompatFeatures,
+    pub s_feature_ro_compat: u32,
+    // 0x30
+    pub s_uuid: [u8; 16],
+    // 0x40
+    /// number of file systems sharing the journal
+    pub s_nr_users: u32,
+    /// location of the dynamic superblock copy
+    pub s_dynsuper: u32,
+    /// limit of journal blocks per transaction
+    pub s_max_transaction: u32,
+    /// limit of data blocks per transaction
+    pub s_max_trans_data: u32,
+    // 0x50
+    pub s_checksum_type: u8,
+    pub s_padding2: [u8; 3],
+    /// number of fast commit blocks at the end of the journal
+    pub s_num_fc_blks: u32,
+    /// block number of the head of the log, for journal_dev only
+    pub s_head: u32,
+    pub s_padding: [u32; 40],
+    // 0xFC
+    /// crc32c(superblock)
+    pub s_checksum: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(BigEndian)]
+pub struct JournalCommitBlock {
+    pub h_header: JournalHeader,
+    pub h_chksum_type: u8,
+    pub h_chksum_size: u8,
+    pub h_padding: [u8; 2],
+    /// crc32c of the commit block with checksum v2/v3, `h_chksum[0]` only
+    pub h_chksum: [u32; 8],
+    // 0x30
+    pub h_commit_sec: u64,
+    pub h_commit_nsec: u32,
+}
+
+/// Fast commit blocks are a stream of tag-length-value records, little-endian
+/// unlike the rest of the journal.
+pub const EXT4_FC_TAG_ADD_RANGE: u16 = 1;
+pub const EXT4_FC_TAG_DEL_RANGE: u16 = 2;
+pub const EXT4_FC_TAG_CREAT: u16 = 3;
+pub const EXT4_FC_TAG_LINK: u16 = 4;
+pub const EXT4_FC_TAG_UNLINK: u16 = 5;
+pub const EXT4_FC_TAG_INODE: u16 = 6;
+pub const EXT4_FC_TAG_PAD: u16 = 7;
+pub const EXT4_FC_TAG_TAIL: u16 = 8;
+pub const EXT4_FC_TAG_HEAD: u16 = 9;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcTl {
+    /// one of the `EXT4_FC_TAG_*` values
+    pub fc_tag: u16,
+    /// length of the value following this header
+    pub fc_len: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcHead {
+    /// fast commit features, none are defined yet
+    pub fc_features: u32,
+    /// transaction the fast commits belong to
+    pub fc_tid: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcAddRange {
+    pub fc_ino: u32,
+    /// logical range and where it is now mapped
+    pub fc_ex: Ext4Extent,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcDelRange {
+    pub fc_ino: u32,
+    /// first logical block unmapped
+    pub fc_lblk: u32,
+    /// number of blocks unmapped
+    pub fc_len: u32,
+}
+
+/// Value of the create, link and unlink tags, the name follows
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcDentryInfo {
+    pub fc_parent_ino: u32,
+    pub fc_ino: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Nom)]
+#[nom(LittleEndian)]
+pub struct Ext4FcTail {
+    /// transaction the fast commit belongs to
+    pub fc_tid: u32,
+    /// crc32c of every tag since the previous tail, up to `fc_tid`
+    pub fc_crc: u32,
+}
+
 bitflags! {
     #[derive(Default, Debug, Clone, Copy)]
     pub struct CompatFeatures: u32 {
@@ -689,6 +1123,37 @@ bitflags! {
         /// 0x0001_0000: Orphan cleanup needed (RO_COMPAT_ORPHAN_PRESENT)
         const ORPHAN_PRESENT    = 0x0001_0000;
     }
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JournalCompatFeatures: u32 {
+        /// 0x0000_0001: crc32 of the transaction data in the commit block (JBD2_FEATURE_COMPAT_CHECKSUM)
+        const CHECKSUM         = 0x0000_0001;
+    }
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JournalIncompatFeatures: u32 {
+        /// 0x0000_0001: Revoke blocks (JBD2_FEATURE_INCOMPAT_REVOKE)
+        const REVOKE           = 0x0000_0001;
+        /// 0x0000_0002: 64-bit block numbers in tags (JBD2_FEATURE_INCOMPAT_64BIT)
+        const _64BIT           = 0x0000_0002;
+        /// 0x0000_0004: Commit blocks written without waiting (JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT)
+        const ASYNC_COMMIT     = 0x0000_0004;
+        /// 0x0000_0008: 16-bit block tag checksums (JBD2_FEATURE_INCOMPAT_CSUM_V2)
+        const CSUM_V2          = 0x0000_0008;
+        /// 0x0000_0010: 32-bit block tag checksums (JBD2_FEATURE_INCOMPAT_CSUM_V3)
+        const CSUM_V3          = 0x0000_0010;
+        /// 0x0000_0020: Fast commit area after the log (JBD2_FEATURE_INCOMPAT_FAST_COMMIT)
+        const FAST_COMMIT      = 0x0000_0020;
+    }
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JournalTagFlags: u32 {
+        /// 0x1: the logged block started with the journal magic, which was zeroed
+        const ESCAPE           = 0x1;
+        /// 0x2: the tag is not followed by a UUID
+        const SAME_UUID        = 0x2;
+        /// 0x4: block deleted by this transaction
+        const DELETED          = 0x4;
+        /// 0x8: last tag of the descriptor block
+        const LAST_TAG         = 0x8;
+    }
     #[derive(Default, Debug, Clone, Copy)]
     pub struct BgFlags: u16 {
         /// 0x0001: inode table and bitmap are not initialized
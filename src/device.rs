@@ -0,0 +1,290 @@
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Sector size assumed for devices that do not report one
+pub const DEFAULT_SECTOR_SIZE: u64 = 512;
+
+/// Storage a file system is read from: an image in memory, an image file or a disk.
+/// Reads are positional so a device can be shared without a cursor, including
+/// between threads.
+pub trait BlockDevice: Send + Sync {
+    /// Fills `buf` with the bytes starting at `offset`, failing when they run past
+    /// the end of the device
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Size of the device in bytes
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Smallest unit the device reads, file system blocks are a multiple of it
+    fn block_size(&self) -> u64 {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    /// The whole device when it is held in memory, blocks are then borrowed
+    /// instead of copied
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// `len` bytes from `offset`, `None` past the end of the device or when the
+    /// read fails
+    fn read_bytes(&self, offset: u64, len: usize) -> Option<Cow<'_, [u8]>> {
+        if let Some(slice) = self.as_slice() {
+            let start = usize::try_from(offset).ok()?;
+            return slice.get(start..start.checked_add(len)?).map(Cow::Borrowed);
+        }
+        let mut buf = vec![0; len];
+        self.read_exact_at(offset, &mut buf).ok()?;
+        Some(Cow::Owned(buf))
+    }
+
+    /// Block `block` of a device split in blocks of `block_size` bytes
+    fn read_block(&self, block: u64, block_size: u64) -> Option<Cow<'_, [u8]>> {
+        self.read_bytes(block.checked_mul(block_size)?, block_size as usize)
+    }
+}
+
+/// Bytes of a block: borrowed from an in-memory device or the overlay, read from
+/// the device, or shared with the block cache
+#[derive(Debug, Clone)]
+pub enum BlockBytes<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+    /// `range` of a cached block
+    Shared(Arc<[u8]>, Range<usize>),
+}
+
+impl BlockBytes<'_> {
+    /// `range` of the bytes, without copying them
+    pub fn slice(self, range: Range<usize>) -> Option<Self> {
+        match self {
+            Self::Borrowed(data) => data.get(range).map(Self::Borrowed),
+            Self::Owned(mut data) => {
+                data.get(range.clone())?;
+                data.truncate(range.end);
+                data.drain(..range.start);
+                Some(Self::Owned(data))
+            }
+            Self::Shared(block, shared) => {
+                let start = shared.start.checked_add(range.start)?;
+                let end = shared.start.checked_add(range.end)?;
+                (start <= end && end <= shared.end).then_some(Self::Shared(block, start..end))
+            }
+        }
+    }
+
+    pub fn into_owned(self) -> Vec<u8> {
+        match self {
+            Self::Owned(data) => data,
+            data => data.to_vec(),
+        }
+    }
+}
+
+impl<'a> BlockBytes<'a> {
+    /// Borrowed bytes stay borrowed, shared ones are copied
+    pub fn into_cow(self) -> Cow<'a, [u8]> {
+        match self {
+            Self::Borrowed(data) => Cow::Borrowed(data),
+            data => Cow::Owned(data.into_owned()),
+        }
+    }
+}
+
+impl Deref for BlockBytes<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Borrowed(data) => data,
+            Self::Owned(data) => data,
+            Self::Shared(block, range) => &block[range.clone()],
+        }
+    }
+}
+
+impl<'a> From<Cow<'a, [u8]>> for BlockBytes<'a> {
+    fn from(data: Cow<'a, [u8]>) -> Self {
+        match data {
+            Cow::Borrowed(data) => Self::Borrowed(data),
+            Cow::Owned(data) => Self::Owned(data),
+        }
+    }
+}
+
+impl From<Arc<[u8]>> for BlockBytes<'_> {
+    fn from(block: Arc<[u8]>) -> Self {
+        let len = <[u8]>::len(&block);
+        Self::Shared(block, 0..len)
+    }
+}
+
+/// `range` of a block, still borrowed if the block was
+pub(crate) fn sub_bytes(data: Cow<'_, [u8]>, range: Range<usize>) -> Option<Cow<'_, [u8]>> {
+    match data {
+        Cow::Borrowed(data) => data.get(range).map(Cow::Borrowed),
+        Cow::Owned(data) => data.get(range).map(|data| Cow::Owned(data.to_vec())),
+    }
+}
+
+fn read_from_slice(slice: &[u8], offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let src = usize::try_from(offset)
+        .ok()
+        .and_then(|start| slice.get(start..start.checked_add(buf.len())?))
+        .ok_or(io::ErrorKind::UnexpectedEof)?;
+    buf.copy_from_slice(src);
+    Ok(())
+}
+
+impl BlockDevice for [u8] {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_from_slice(self, offset, buf)
+    }
+
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for Vec<u8> {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_from_slice(self, offset, buf)
+    }
+
+    fn len(&self) -> u64 {
+        Vec::len(self) as u64
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+/// Forwards every method, including the provided ones a device may override
+macro_rules! forward_block_device {
+    ($($pointer:ty),*) => {$(
+        impl<T: BlockDevice + ?Sized> BlockDevice for $pointer {
+            fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+                (**self).read_exact_at(offset, buf)
+            }
+
+            fn len(&self) -> u64 {
+                (**self).len()
+            }
+
+            fn block_size(&self) -> u64 {
+                (**self).block_size()
+            }
+
+            fn as_slice(&self) -> Option<&[u8]> {
+                (**self).as_slice()
+            }
+
+            fn read_bytes(&self, offset: u64, len: usize) -> Option<Cow<'_, [u8]>> {
+                (**self).read_bytes(offset, len)
+            }
+        }
+    )*};
+}
+
+forward_block_device!(&T, Box<T>, Arc<T>);
+
+/// An image file or a disk read with positional reads, nothing of it is kept in
+/// memory
+#[derive(Debug)]
+pub struct FileDevice {
+    file: File,
+    len: u64,
+}
+
+impl FileDevice {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Takes the size from the end of the file, which unlike its metadata also
+    /// works for block devices
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = (&file).seek(SeekFrom::End(0))?;
+        Ok(Self { file, len })
+    }
+}
+
+impl BlockDevice for FileDevice {
+    #[cfg(unix)]
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => {
+                    buf = &mut buf[read..];
+                    offset += read as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// An image file mapped in memory, blocks are borrowed from the mapping
+#[derive(Debug)]
+pub struct MmapDevice {
+    map: Mmap,
+}
+
+impl MmapDevice {
+    /// Maps `path` read-only.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped, see
+    /// `memmap2::Mmap::map`.
+    pub unsafe fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        unsafe { Self::new(&file) }
+    }
+
+    /// # Safety
+    ///
+    /// Same as `open`.
+    pub unsafe fn new(file: &File) -> io::Result<Self> {
+        Ok(Self { map: unsafe { Mmap::map(file)? } })
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_from_slice(&self.map, offset, buf)
+    }
+
+    fn len(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.map)
+    }
+}
@@ -0,0 +1,572 @@
+use crate::block_map::{BlockMap, BlockRun};
+use crate::csum::crc32c;
+use crate::defs::{
+    BlockContents, Ext4ExtentHeader, Ext4FcAddRange, Ext4FcDelRange, Ext4FcDentryInfo, Ext4FcHead, Ext4FcTail,
+    Ext4FcTl, Ext4Inode, FileType, IncompatFeatures, InodeFlags, EXT4_EXT_MAGIC, EXT4_FC_TAG_ADD_RANGE,
+    EXT4_FC_TAG_CREAT, EXT4_FC_TAG_DEL_RANGE, EXT4_FC_TAG_HEAD, EXT4_FC_TAG_INODE, EXT4_FC_TAG_LINK, EXT4_FC_TAG_PAD,
+    EXT4_FC_TAG_TAIL, EXT4_FC_TAG_UNLINK, EXT4_GOOD_OLD_INODE_SIZE, EXT4_I_BLOCK_OFFSET, EXT4_NAME_LEN, EXT4_N_BLOCKS,
+};
+use crate::device::{BlockBytes, BlockDevice};
+use crate::fs_parser::{Err, Ext4Fs};
+use crate::journal::{BlockOverlay, Journal};
+use nom_derive::Parse;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::size_of;
+
+/// Tag and length heading every record
+const EXT4_FC_TAG_BASE_LEN: usize = 4;
+/// The head carries no feature the kernel knows of yet
+const EXT4_FC_SUPPORTED_FEATURES: u32 = 0;
+/// Offset of `i_generation`, replay takes the inode from there on from the fast commit
+const EXT4_INODE_GENERATION_OFFSET: usize = 0x64;
+/// Size of an extent header or entry, `i_block` holds a header and four extents
+const EXT4_EXTENT_ENTRY_SIZE: usize = 12;
+
+/// A record of the fast commit area. Fast commits log what changed rather than
+/// whole blocks: mapped ranges, directory entries and raw inodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FastCommitTag {
+    /// first record of the area
+    Head { features: u32, tid: u32 },
+    /// `run` of inode `i_no` is now mapped, over whatever it was mapped to
+    AddRange { i_no: u64, run: BlockRun },
+    /// `len` blocks of inode `i_no` from `logical` are now a hole
+    DelRange { i_no: u64, logical: u64, len: u64 },
+    /// `name` was created in `parent` for the new inode `i_no`
+    Create { parent: u64, i_no: u64, name: Vec<u8> },
+    Link { parent: u64, i_no: u64, name: Vec<u8> },
+    Unlink { parent: u64, i_no: u64, name: Vec<u8> },
+    /// on-disk bytes of inode `i_no` at the time of the fast commit
+    Inode { i_no: u64, raw: Vec<u8> },
+    /// filler up to the end of the block
+    Pad,
+    /// closes a fast commit, `crc` covers every record since the previous tail
+    Tail { tid: u32, crc: u32 },
+}
+
+/// A change of an inode's block map replayed from a fast commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeEdit {
+    Map(BlockRun),
+    Unmap { logical: u64, len: u64 },
+}
+
+impl RangeEdit {
+    pub fn apply(&self, block_map: &mut BlockMap) {
+        match *self {
+            Self::Map(run) => block_map.map(run),
+            Self::Unmap { logical, len } => block_map.unmap(logical, len),
+        }
+    }
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Decodes a record, `None` when the tag is unknown or its value has a size the
+/// kernel would reject.
+fn parse_tag(tag: u16, value: &[u8]) -> Option<FastCommitTag> {
+    let dentry = || {
+        let (name, info) = Ext4FcDentryInfo::parse(value).ok()?;
+        (1..=EXT4_NAME_LEN)
+            .contains(&name.len())
+            .then(|| (info.fc_parent_ino as u64, info.fc_ino as u64, name.to_vec()))
+    };
+    let tag = match tag {
+        EXT4_FC_TAG_ADD_RANGE if value.len() == size_of::<Ext4FcAddRange>() => {
+            let (_, range) = Ext4FcAddRange::parse(value).ok()?;
+            let extent = range.fc_ex;
+            let run = BlockRun {
+                logical: extent.ee_block as u64,
+                physical: extent.ee_start(),
+                len: extent.len() as u64,
+                uninit: extent.is_uninit(),
+            };
+            FastCommitTag::AddRange { i_no: range.fc_ino as u64, run }
+        }
+        EXT4_FC_TAG_DEL_RANGE if value.len() == size_of::<Ext4FcDelRange>() => {
+            let (_, range) = Ext4FcDelRange::parse(value).ok()?;
+            FastCommitTag::DelRange { i_no: range.fc_ino as u64, logical: range.fc_lblk as u64, len: range.fc_len as u64 }
+        }
+        EXT4_FC_TAG_CREAT => {
+            let (parent, i_no, name) = dentry()?;
+            FastCommitTag::Create { parent, i_no, name }
+        }
+        EXT4_FC_TAG_LINK => {
+            let (parent, i_no, name) = dentry()?;
+            FastCommitTag::Link { parent, i_no, name }
+        }
+        EXT4_FC_TAG_UNLINK => {
+            let (parent, i_no, name) = dentry()?;
+            FastCommitTag::Unlink { parent, i_no, name }
+        }
+        EXT4_FC_TAG_INODE if value.len() >= 4 + EXT4_GOOD_OLD_INODE_SIZE => {
+            FastCommitTag::Inode { i_no: le32(value) as u64, raw: value[4..].to_vec() }
+        }
+        EXT4_FC_TAG_PAD => FastCommitTag::Pad,
+        EXT4_FC_TAG_TAIL if value.len() >= size_of::<Ext4FcTail>() => {
+            let (_, tail) = Ext4FcTail::parse(value).ok()?;
+            FastCommitTag::Tail { tid: tail.fc_tid, crc: tail.fc_crc }
+        }
+        EXT4_FC_TAG_HEAD if value.len() == size_of::<Ext4FcHead>() => {
+            let (_, head) = Ext4FcHead::parse(value).ok()?;
+            FastCommitTag::Head { features: head.fc_features, tid: head.fc_tid }
+        }
+        _ => return None,
+    };
+    Some(tag)
+}
+
+impl<'a> Journal<'a> {
+    /// Sequence of the first transaction missing from the log, the one that was
+    /// running when the log ended. Fast commits carry its sequence.
+    pub fn next_sequence(&self) -> u32 {
+        self.transactions()
+            .last()
+            .map_or(self.super_block.s_sequence, |transaction| transaction.sequence.wrapping_add(1))
+    }
+
+    /// Records of the fast commit area belonging to transaction `tid`, up to the
+    /// last tail whose checksum matches. The area must open with a head for `tid`,
+    /// anything else is left over from an earlier transaction.
+    pub fn fast_commit_tags(&self, tid: u32) -> Vec<FastCommitTag> {
+        let mut tags = Vec::new();
+        let mut committed = 0;
+        let mut crc = 0;
+        // like the kernel, the area starts one block past the end of the log
+        'area: for block in self.last() + 1..self.super_block.s_maxlen as u64 {
+            let Some(data) = self.block(block) else {
+                break;
+            };
+            let mut offset = 0;
+            // records never cross a block, the last one is padded up to the end
+            while offset + EXT4_FC_TAG_BASE_LEN <= data.len() {
+                let Ok((value, tl)) = Ext4FcTl::parse(&data[offset..]) else {
+                    break 'area;
+                };
+                let end = offset + EXT4_FC_TAG_BASE_LEN + tl.fc_len as usize;
+                let Some(tag) = value.get(..tl.fc_len as usize).and_then(|value| parse_tag(tl.fc_tag, value)) else {
+                    break 'area;
+                };
+                match tag {
+                    FastCommitTag::Head { features, tid: head_tid } => {
+                        if features & !EXT4_FC_SUPPORTED_FEATURES != 0 || head_tid != tid {
+                            break 'area;
+                        }
+                        crc = crc32c(crc, &data[offset..end]);
+                    }
+                    _ if tags.is_empty() => break 'area,
+                    FastCommitTag::Tail { tid: tail_tid, crc: tail_crc } => {
+                        // the checksum stops short of itself
+                        crc = crc32c(crc, &data[offset..offset + EXT4_FC_TAG_BASE_LEN + 4]);
+                        if tail_tid != tid || tail_crc != crc {
+                            break 'area;
+                        }
+                        committed = tags.len() + 1;
+                        crc = 0;
+                    }
+                    _ => crc = crc32c(crc, &data[offset..end]),
+                }
+                tags.push(tag);
+                offset = end;
+            }
+        }
+        tags.truncate(committed);
+        tags
+    }
+}
+
+impl BlockOverlay {
+    /// Replays fast commit records on top of an overlay holding the replayed log,
+    /// in record order like the kernel. Inodes are written back to the inode table
+    /// and marked in the bitmaps. Mapping and directory changes are kept as edits
+    /// applied when reading, as writing them back would mean allocating blocks.
+    /// Free counts and bitmap checksums are left as they were.
+    pub fn replay_fast_commits<D: BlockDevice + ?Sized>(&mut self, device: &D, tags: &[FastCommitTag]) -> Result<(), Err> {
+        // the file system reads through the overlay, lend it and take it back
+        Ext4Fs::lend_overlay(device, self, |fs| fs.replay_fc_tags(tags))
+    }
+}
+
+/// `file_type` of a directory entry, the kernel's `EXT4_FT_*` values
+fn dir_entry_type(ty: FileType) -> u8 {
+    match ty {
+        FileType::Regular => 1,
+        FileType::Dir => 2,
+        FileType::CharDev => 3,
+        FileType::BlockDev => 4,
+        FileType::Fifo => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+        FileType::Unknown(_) => 0,
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    fn replay_fc_tags(&mut self, tags: &[FastCommitTag]) {
+        // inodes created by the fast commits and their parent
+        let mut created = BTreeMap::new();
+        for tag in tags {
+            let (blocks, edit) = match *tag {
+                FastCommitTag::Inode { i_no, ref raw } => (self.replay_fc_inode(i_no, raw), None),
+                FastCommitTag::AddRange { i_no, run } => {
+                    let edit = RangeEdit::Map(run);
+                    self.replay_fc_range(i_no, edit).map_or((BTreeMap::new(), None), |blocks| (blocks, Some((i_no, edit))))
+                }
+                FastCommitTag::DelRange { i_no, logical, len } => {
+                    let edit = RangeEdit::Unmap { logical, len };
+                    self.replay_fc_range(i_no, edit).map_or((BTreeMap::new(), None), |blocks| (blocks, Some((i_no, edit))))
+                }
+                _ => (BTreeMap::new(), None),
+            };
+            let overlay = self.overlay_mut();
+            for (block, data) in blocks {
+                overlay.insert(block, data);
+            }
+            if let Some((i_no, edit)) = edit {
+                overlay.range_edits.entry(i_no).or_default().push(edit);
+            }
+            match *tag {
+                FastCommitTag::Create { parent, i_no, ref name } => {
+                    created.insert(i_no, parent);
+                    overlay.dir_entries.insert((parent, name.clone()), Some(i_no));
+                }
+                FastCommitTag::Link { parent, i_no, ref name } => {
+                    overlay.dir_entries.insert((parent, name.clone()), Some(i_no));
+                }
+                FastCommitTag::Unlink { parent, ref name, .. } => {
+                    overlay.dir_entries.insert((parent, name.clone()), None);
+                }
+                _ => {}
+            }
+        }
+        self.replay_fc_listings(&created);
+    }
+
+    /// Rebuilds the listing of every directory whose entries changed. Directories
+    /// created by the fast commits own no block yet and get `.` and `..`.
+    fn replay_fc_listings(&mut self, created: &BTreeMap<u64, u64>) {
+        let Some(dir_entries) = self.overlay().map(|overlay| &overlay.dir_entries) else {
+            return;
+        };
+        let dirs: BTreeSet<u64> = dir_entries.keys().map(|(dir, _)| *dir).chain(created.keys().copied()).collect();
+        let mut listings = BTreeMap::new();
+        let mut dots = Vec::new();
+        for dir in dirs {
+            let Some(inode) = self.get_inode(dir).filter(|inode| inode.i_mode.ty.is_dir()) else {
+                continue;
+            };
+            let mut entries: Vec<(Vec<u8>, u64)> = match self.get_inode_block_contents(&inode) {
+                Some(BlockContents::Dentries(entries)) => entries
+                    .filter(|d_entry| d_entry.inode != 0)
+                    .map(|d_entry| (d_entry.name_bytes().to_vec(), d_entry.inode as u64))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if entries.is_empty()
+                && let Some(&parent) = created.get(&dir)
+            {
+                for (name, i_no) in [(&b"."[..], dir), (&b".."[..], parent)] {
+                    entries.push((name.to_vec(), i_no));
+                    dots.push(((dir, name.to_vec()), Some(i_no)));
+                }
+            }
+            for ((_, name), &i_no) in dir_entries.range((dir, Vec::new())..(dir + 1, Vec::new())) {
+                entries.retain(|(other, _)| other != name);
+                entries.extend(i_no.map(|i_no| (name.clone(), i_no)));
+            }
+            let listing = entries.iter().flat_map(|(name, i_no)| self.dir_entry_bytes(name, *i_no)).collect();
+            listings.insert(dir, listing);
+        }
+        let overlay = self.overlay_mut();
+        overlay.listings = listings;
+        overlay.dir_entries.extend(dots);
+    }
+
+    /// The inode table block holding `i_no` with the inode taken from the fast
+    /// commit, and the inode bitmap marking it in use. As in the kernel, `i_block`
+    /// is kept from the table for extent-mapped inodes, whose ranges are replayed
+    /// separately, and taken from the fast commit for inline data.
+    fn replay_fc_inode(&self, i_no: u64, fc_raw: &[u8]) -> BTreeMap<u64, Vec<u8>> {
+        let mut patched = BTreeMap::new();
+        let inode_size = self.super_block().s_inode_size as usize;
+        let Some((block, offset)) = self.inode_location(i_no) else {
+            return patched;
+        };
+        let Some(mut data) = self.get_block(block).map(BlockBytes::into_owned) else {
+            return patched;
+        };
+        let Some(raw) = data.get_mut(offset..offset + inode_size) else {
+            return patched;
+        };
+        let len = fc_raw.len().min(inode_size);
+        raw[..EXT4_I_BLOCK_OFFSET].copy_from_slice(&fc_raw[..EXT4_I_BLOCK_OFFSET]);
+        raw[EXT4_INODE_GENERATION_OFFSET..len].copy_from_slice(&fc_raw[EXT4_INODE_GENERATION_OFFSET..len]);
+        let Some(mut inode) = Ext4Inode::parse_sized(raw) else {
+            return patched;
+        };
+        inode.i_no = i_no;
+        let i_block = &mut raw[EXT4_I_BLOCK_OFFSET..EXT4_INODE_GENERATION_OFFSET];
+        if inode.i_flags.contains(InodeFlags::EXTENTS) {
+            if !Ext4ExtentHeader::parse(&*i_block).is_ok_and(|(_, header)| header.is_header()) {
+                // start over from an empty tree
+                i_block.fill(0);
+                i_block[..2].copy_from_slice(&EXT4_EXT_MAGIC.to_le_bytes());
+                let max = (EXT4_N_BLOCKS * 4 / EXT4_EXTENT_ENTRY_SIZE - 1) as u16;
+                i_block[4..6].copy_from_slice(&max.to_le_bytes());
+            }
+        } else if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            i_block.copy_from_slice(&fc_raw[EXT4_I_BLOCK_OFFSET..EXT4_INODE_GENERATION_OFFSET]);
+        }
+        if self.has_metadata_csum() {
+            self.set_inode_csum(&inode, raw);
+        }
+        patched.insert(block, data);
+        self.mark_inode(&mut patched, i_no, true);
+        patched
+    }
+
+    /// Block bitmaps with the blocks `edit` unmaps from inode `i_no` released and
+    /// those it maps marked in use, `None` when the inode cannot be read.
+    fn replay_fc_range(&self, i_no: u64, edit: RangeEdit) -> Option<BTreeMap<u64, Vec<u8>>> {
+        let block_map = self.get_inode(i_no)?.block_map(self);
+        let (logical, len) = match edit {
+            RangeEdit::Map(run) => (run.logical, run.len),
+            RangeEdit::Unmap { logical, len } => (logical, len),
+        };
+        let mut patched = BTreeMap::new();
+        for old in block_map.range(logical, len) {
+            self.mark_blocks(&mut patched, old.physical, old.len, false);
+        }
+        if let RangeEdit::Map(run) = edit {
+            self.mark_blocks(&mut patched, run.physical, run.len, true);
+        }
+        Some(patched)
+    }
+
+    pub(crate) fn mark_inode(&self, patched: &mut BTreeMap<u64, Vec<u8>>, i_no: u64, used: bool) {
+        let inodes_per_group = self.super_block().s_inodes_per_group as u64;
+        let Some(index) = i_no.checked_sub(1) else {
+            return;
+        };
+        let Some(group_desc) = self.group_descs.get((index / inodes_per_group) as usize) else {
+            return;
+        };
+        let block = group_desc.inode_bitmap();
+        self.mark_bit(patched, block, || self.get_block(block), index % inodes_per_group, used);
+    }
+
+    /// Sets or clears the bits of `len` blocks from `block`, one bit per cluster
+    /// under bigalloc
+    pub(crate) fn mark_blocks(&self, patched: &mut BTreeMap<u64, Vec<u8>>, block: u64, len: u64, used: bool) {
+        for block in block..block.saturating_add(len) {
+            let Some((group, bit)) = self.block_bit(block) else {
+                continue;
+            };
+            let Some(group_desc) = self.group_descs.get(group as usize) else {
+                return;
+            };
+            // a `BLOCK_UNINIT` group starts from the bitmap the kernel would build
+            self.mark_bit(patched, group_desc.block_bitmap(), || self.block_bitmap(group), bit, used);
+        }
+    }
+
+    /// Sets or clears `bit` of bitmap block `block`, whose patched copy is kept in
+    /// `patched` and starts from `read`. A bit past the block is skipped.
+    fn mark_bit<'f>(
+        &'f self,
+        patched: &mut BTreeMap<u64, Vec<u8>>,
+        block: u64,
+        read: impl FnOnce() -> Option<BlockBytes<'f>>,
+        bit: u64,
+        used: bool,
+    ) {
+        let data = match patched.entry(block) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match read() {
+                Some(data) => entry.insert(data.into_owned()),
+                None => return,
+            },
+        };
+        let Some(byte) = data.get_mut((bit / 8) as usize) else {
+            return;
+        };
+        if used {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+
+    /// A directory entry for the replayed listing, as short as the name allows
+    fn dir_entry_bytes(&self, name: &[u8], i_no: u64) -> Vec<u8> {
+        let file_type = match self.get_inode(i_no) {
+            Some(inode) if self.super_block().s_feature_incompat.contains(IncompatFeatures::FILETYPE) => {
+                dir_entry_type(inode.i_mode.ty)
+            }
+            _ => 0,
+        };
+        let rec_len = (8 + name.len()).next_multiple_of(4);
+        let mut entry = Vec::with_capacity(rec_len);
+        entry.extend_from_slice(&(i_no as u32).to_le_bytes());
+        entry.extend_from_slice(&(rec_len as u16).to_le_bytes());
+        entry.extend_from_slice(&[name.len() as u8, file_type]);
+        entry.extend_from_slice(name);
+        entry.resize(rec_len, 0);
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defs::{BgFlags, JournalIncompatFeatures, RoCompatFeatures, EXT4_SUPER_MAGIC};
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Non-zero spans of a fast commit block for tid 2, which e2fsck replays. The
+    /// expected records are those `debugfs logdump` lists.
+    const FAST_COMMIT: &[(usize, &str)] = &[
+        (0, "090008000000000002000000060004010e000000a4810000120000006eedd26a6eedd26a6eedd26a00000000000002000800000000000800000000000af30100040000000000000000000000010000002308"),
+        (144, "e3cc0000200020a1"),
+        (164, "6eedd26a"),
+        (276, "03000f00020000000e0000006e657766696c65010010000e00000000000000010000002308000005000900020000000c00000061040010000d0000000e000000686172646c696e6b060004010f000000ed410000001000006eedd26a6eedd26a6eedd26a00000000000002000800000000000800000000000af30100040000000000000000000000010000002208"),
+        (480, "a5a400002000efed"),
+        (500, "6eedd26a"),
+        (612, "03000b000d0000000f0000007375620800890d02000000cbdfc944"),
+    ];
+
+    /// Journal of 8 blocks with the last 2 reserved for fast commits, whose area
+    /// starts at block 7 like the kernel's
+    fn journal_image() -> Vec<u8> {
+        let mut image = vec![0; 8 * BLOCK_SIZE];
+        let super_block = [
+            (0x00, 0xc03b_3998),
+            (0x04, 4),
+            (0x0c, BLOCK_SIZE as u32),
+            (0x10, 8),
+            (0x14, 1),
+            (0x18, 2),
+            (0x28, JournalIncompatFeatures::FAST_COMMIT.bits()),
+            (0x54, 2),
+        ];
+        for (offset, value) in super_block {
+            image[offset..offset + 4].copy_from_slice(&u32::to_be_bytes(value));
+        }
+        for &(offset, hex) in FAST_COMMIT {
+            let start = 7 * BLOCK_SIZE + offset;
+            for (i, byte) in (0..hex.len()).step_by(2).enumerate() {
+                image[start + i] = u8::from_str_radix(&hex[byte..byte + 2], 16).unwrap();
+            }
+        }
+        image
+    }
+
+    fn tags(image: &[u8], tid: u32) -> Vec<FastCommitTag> {
+        let block_map = BlockMap::new(vec![BlockRun { logical: 0, physical: 0, len: 8, uninit: false }]);
+        Journal::new(&image, block_map, BLOCK_SIZE as u64).unwrap().fast_commit_tags(tid)
+    }
+
+    #[test]
+    fn committed_tags() {
+        let tags = tags(&journal_image(), 2);
+        let inode = |tag: &FastCommitTag| match tag {
+            FastCommitTag::Inode { i_no, raw } => Some((*i_no, raw.len())),
+            _ => None,
+        };
+        assert_eq!(tags.len(), 9);
+        assert_eq!(tags[0], FastCommitTag::Head { features: 0, tid: 2 });
+        assert_eq!(inode(&tags[1]), Some((14, 256)));
+        assert_eq!(tags[2], FastCommitTag::Create { parent: 2, i_no: 14, name: b"newfile".to_vec() });
+        let run = BlockRun { logical: 0, physical: 2083, len: 1, uninit: false };
+        assert_eq!(tags[3], FastCommitTag::AddRange { i_no: 14, run });
+        assert_eq!(tags[4], FastCommitTag::Unlink { parent: 2, i_no: 12, name: b"a".to_vec() });
+        assert_eq!(tags[5], FastCommitTag::Link { parent: 13, i_no: 14, name: b"hardlink".to_vec() });
+        assert_eq!(inode(&tags[6]), Some((15, 256)));
+        assert_eq!(tags[7], FastCommitTag::Create { parent: 13, i_no: 15, name: b"sub".to_vec() });
+        assert_eq!(tags[8], FastCommitTag::Tail { tid: 2, crc: 0x44c9_dfcb });
+    }
+
+    #[test]
+    fn other_transaction() {
+        assert!(tags(&journal_image(), 3).is_empty());
+    }
+
+    #[test]
+    fn bad_tail_crc() {
+        let mut image = journal_image();
+        // the crc is the last word of the tail record
+        image[7 * BLOCK_SIZE + 635] ^= 1;
+        assert!(tags(&image, 2).is_empty());
+    }
+
+    /// A 1k-block bigalloc file system with 16-block clusters and one group, whose
+    /// block bitmap at block 16 holds `on_disk`
+    fn bigalloc_fs(bg_flags: u16, on_disk: u8) -> Ext4Fs<Vec<u8>> {
+        let mut image = vec![0; 2048 * 1024];
+        let super_block = [
+            (0x00, 16),
+            (0x04, 2048),
+            (0x1c, 4),
+            (0x20, 8192 * 16),
+            (0x24, 8192),
+            (0x28, 16),
+            (0x4c, 1),
+            (0x64, RoCompatFeatures::BIGALLOC.bits()),
+        ];
+        for (offset, value) in super_block {
+            image[1024 + offset..1024 + offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        image[1024 + 0x38..1024 + 0x3a].copy_from_slice(&EXT4_SUPER_MAGIC.to_le_bytes());
+        image[1024 + 0x58..1024 + 0x5a].copy_from_slice(&256u16.to_le_bytes());
+        // descriptor in the block after the super block's: bitmaps at 16 and 17,
+        // inode table at 18
+        for (offset, value) in [(0x00, 16u32), (0x04, 17), (0x08, 18)] {
+            image[2048 + offset..2048 + offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        image[2048 + 0x12..2048 + 0x14].copy_from_slice(&bg_flags.to_le_bytes());
+        image[16 * 1024..17 * 1024].fill(on_disk);
+        Ext4Fs::new(image).unwrap()
+    }
+
+    #[test]
+    fn bigalloc_block_bits() {
+        let fs = bigalloc_fs(0, 0);
+        let mut patched = BTreeMap::new();
+        // blocks 1000-1039 lie in clusters 62-64
+        fs.mark_blocks(&mut patched, 1000, 40, true);
+        let bitmap = &patched[&16];
+        assert_eq!(bitmap[7..9], [0b1100_0000, 0b0000_0001]);
+        assert_eq!(bitmap.iter().map(|byte| byte.count_ones()).sum::<u32>(), 3);
+        // the last cluster, whose bit would lie far past the bitmap counted per block
+        fs.mark_blocks(&mut patched, 8192 * 16 - 1, 1, true);
+        assert_eq!(patched[&16][1023], 0x80);
+    }
+
+    #[test]
+    fn bigalloc_uninit_bitmap() {
+        let fs = bigalloc_fs(BgFlags::BLOCK_UNINIT.bits(), 0xaa);
+        let mut patched = BTreeMap::new();
+        fs.mark_blocks(&mut patched, 1000, 1, true);
+        // built from the super block, descriptor, bitmaps and inode table in
+        // clusters 0 and 1, and the clusters past the 128 of the group
+        let bitmap = &patched[&16];
+        assert_eq!(bitmap[..16], [0b11, 0, 0, 0, 0, 0, 0, 0b0100_0000, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(bitmap[16..].iter().all(|&byte| byte == 0xff));
+
+        fs.mark_blocks(&mut patched, 16, 16, false);
+        assert_eq!(patched[&16][0], 0b01);
+    }
+
+    #[test]
+    fn bad_value_sizes() {
+        assert_eq!(parse_tag(EXT4_FC_TAG_ADD_RANGE, &[0; 12]), None);
+        assert_eq!(parse_tag(EXT4_FC_TAG_DEL_RANGE, &[0; 16]), None);
+        assert_eq!(parse_tag(EXT4_FC_TAG_CREAT, &[0; 8]), None);
+        assert_eq!(parse_tag(EXT4_FC_TAG_INODE, &[0; 4]), None);
+        assert_eq!(parse_tag(EXT4_FC_TAG_HEAD, &[0; 4]), None);
+        assert_eq!(parse_tag(0, &[]), None);
+    }
+}
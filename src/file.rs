@@ -0,0 +1,324 @@
+use crate::block_map::{BlockMap, ZERO_BLOCK};
+use crate::defs::{Ext4Inode, InodeFlags};
+use crate::device::BlockDevice;
+use crate::fs_parser::{Ext4Fs, LookupError};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+/// Largest part of an extent read at once, longer extents are read in parts
+pub const MAX_READ: u64 = 8 << 20;
+
+/// A file `Ext4Fs::open` can open
+#[derive(Debug, Clone, Copy)]
+pub enum FileRef<'a> {
+    /// resolved with `Ext4Fs::lookup`, symlinks followed
+    Path(&'a str),
+    /// inode number
+    Ino(u64),
+    Inode(Ext4Inode),
+}
+
+impl<'a> From<&'a str> for FileRef<'a> {
+    fn from(path: &'a str) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<u64> for FileRef<'_> {
+    fn from(i_no: u64) -> Self {
+        Self::Ino(i_no)
+    }
+}
+
+impl From<Ext4Inode> for FileRef<'_> {
+    fn from(inode: Ext4Inode) -> Self {
+        Self::Inode(inode)
+    }
+}
+
+impl From<&Ext4Inode> for FileRef<'_> {
+    fn from(inode: &Ext4Inode) -> Self {
+        Self::Inode(*inode)
+    }
+}
+
+/// Where the bytes at a file offset come from
+pub(crate) enum Span<'a> {
+    /// inline data from the offset on
+    Inline(&'a [u8]),
+    /// `count` device blocks from `physical`, holding the file from offset `start`
+    Blocks { start: u64, physical: u64, count: u64 },
+    /// bytes of a hole or unwritten extent
+    Zeros(u64),
+}
+
+/// Where the contents of a file lie, shared by the sync and async file handles
+pub(crate) struct FileLayout {
+    size: u64,
+    block_size: u64,
+    contents: FileContents,
+}
+
+enum FileContents {
+    Mapped(BlockMap),
+    /// inline data is read whole when the file is opened, a size past it reads
+    /// as a hole
+    Inline(Vec<u8>),
+}
+
+impl FileLayout {
+    pub(crate) fn load<D: BlockDevice>(fs: &Ext4Fs<D>, inode: &Ext4Inode) -> Self {
+        let contents = if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            // `i_block` and the `system.data` value, however large `i_size` claims
+            let mut data = fs.inline_data(inode).unwrap_or_default();
+            data.truncate(inode.size() as usize);
+            FileContents::Inline(data)
+        } else {
+            FileContents::Mapped(inode.block_map(fs))
+        };
+        Self { size: inode.size(), block_size: fs.block_size(), contents }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Span holding `pos`, which must be before the end of the file. Mapped spans
+    /// start at the block holding `pos` and run to the end of its extent, at most
+    /// `MAX_READ` bytes.
+    pub(crate) fn span(&self, pos: u64) -> Span<'_> {
+        let block_map = match &self.contents {
+            FileContents::Inline(data) if pos < data.len() as u64 => return Span::Inline(&data[pos as usize..]),
+            FileContents::Inline(_) => return Span::Zeros(self.size - pos),
+            FileContents::Mapped(block_map) => block_map,
+        };
+        let block_size = self.block_size;
+        let logical = pos / block_size;
+        match block_map.find(logical) {
+            Some(run) if !run.uninit => {
+                let end = (run.logical + run.len)
+                    .min(self.size.div_ceil(block_size))
+                    .min(logical + (MAX_READ / block_size).max(1));
+                Span::Blocks { start: logical * block_size, physical: run.physical + logical - run.logical, count: end - logical }
+            }
+            _ => Span::Zeros(self.next_data(pos).unwrap_or(self.size) - pos),
+        }
+    }
+
+    /// First offset from `offset` holding data, `None` past the last data
+    pub(crate) fn next_data(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let block_map = match &self.contents {
+            FileContents::Inline(data) => return (offset < data.len() as u64).then_some(offset),
+            FileContents::Mapped(block_map) => block_map,
+        };
+        let logical = offset / self.block_size;
+        let first = block_map.runs.partition_point(|run| run.logical + run.len <= logical);
+        let run = block_map.runs[first..].iter().find(|run| !run.uninit)?;
+        Some((run.logical * self.block_size).max(offset)).filter(|&start| start < self.size)
+    }
+
+    /// First offset from `offset` in a hole, the end of the file counting as one.
+    /// `None` from the end of the file on.
+    pub(crate) fn next_hole(&self, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let block_map = match &self.contents {
+            FileContents::Inline(data) => return Some((data.len() as u64).clamp(offset, self.size)),
+            FileContents::Mapped(block_map) => block_map,
+        };
+        let mut logical = offset / self.block_size;
+        while let Some(run) = block_map.find(logical).filter(|run| !run.uninit) {
+            logical = run.logical + run.len;
+        }
+        Some((logical * self.block_size).clamp(offset, self.size))
+    }
+}
+
+/// Position `position` leads to from `pos` in a file of `size` bytes
+pub(crate) fn seek_position(pos: u64, size: u64, position: SeekFrom) -> io::Result<u64> {
+    let new_pos = match position {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => size.checked_add_signed(offset),
+        SeekFrom::Current(offset) => pos.checked_add_signed(offset),
+    };
+    new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+}
+
+/// The contents of a file read with `std::io`, see `Ext4Fs::open`. Extents are
+/// read whole, up to `MAX_READ` bytes, and borrowed from devices held in memory.
+/// Holes and unwritten extents read as zeros.
+pub struct Ext4File<'f, D: BlockDevice> {
+    fs: &'f Ext4Fs<D>,
+    inode: Ext4Inode,
+    layout: FileLayout,
+    pos: u64,
+    /// file offset and data of the extent part read last
+    buffer: (u64, Cow<'f, [u8]>),
+}
+
+impl<'f, D: BlockDevice> Ext4File<'f, D> {
+    pub fn inode(&self) -> &Ext4Inode {
+        &self.inode
+    }
+
+    pub fn size(&self) -> u64 {
+        self.layout.size()
+    }
+
+    /// First offset from `offset` holding data, like `lseek` with `SEEK_DATA`.
+    /// Unwritten extents count as holes. `None` past the last data.
+    pub fn next_data(&self, offset: u64) -> Option<u64> {
+        self.layout.next_data(offset)
+    }
+
+    /// First offset from `offset` in a hole, like `lseek` with `SEEK_HOLE`. The end
+    /// of the file counts as a hole, `None` from it on.
+    pub fn next_hole(&self, offset: u64) -> Option<u64> {
+        self.layout.next_hole(offset)
+    }
+
+    /// Moves to `next_data(offset)`, failing with `InvalidInput` where `lseek`
+    /// fails with `ENXIO`
+    pub fn seek_data(&mut self, offset: u64) -> io::Result<u64> {
+        self.pos = self.next_data(offset).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no data past the offset"))?;
+        Ok(self.pos)
+    }
+
+    /// Moves to `next_hole(offset)`, failing with `InvalidInput` where `lseek`
+    /// fails with `ENXIO`
+    pub fn seek_hole(&mut self, offset: u64) -> io::Result<u64> {
+        self.pos = self.next_hole(offset).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset past the end of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+impl<D: BlockDevice> BufRead for Ext4File<'_, D> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let size = self.layout.size();
+        if self.pos >= size {
+            return Ok(&[]);
+        }
+        let (start, data) = &self.buffer;
+        if self.pos.checked_sub(*start).is_none_or(|skip| skip >= data.len() as u64) {
+            match self.layout.span(self.pos) {
+                Span::Inline(data) => return Ok(data),
+                Span::Zeros(len) => return Ok(&ZERO_BLOCK[..len.min(ZERO_BLOCK.len() as u64) as usize]),
+                Span::Blocks { start, physical, count } => {
+                    let data = self.fs.read_blocks(physical, count).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, format!("blocks {physical}..{} cannot be read", physical + count))
+                    })?;
+                    self.buffer = (start, data);
+                }
+            }
+        }
+        let (start, data) = &self.buffer;
+        let end = data.len().min((size - start) as usize);
+        Ok(&data[(self.pos - start) as usize..end])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos += amount as u64;
+    }
+}
+
+impl<D: BlockDevice> Read for Ext4File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let src = self.fill_buf()?;
+        let len = src.len().min(buf.len());
+        buf[..len].copy_from_slice(&src[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for Ext4File<'_, D> {
+    /// Positions past the end are allowed and read nothing
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(self.pos, self.layout.size(), position)?;
+        Ok(self.pos)
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Opens a file for reading with `std::io`, by path, inode number or inode
+    pub fn open<'a>(&self, file: impl Into<FileRef<'a>>) -> Result<Ext4File<'_, D>, LookupError> {
+        let inode = match file.into() {
+            FileRef::Path(path) => self.lookup(path, true)?.1,
+            FileRef::Ino(i_no) => self.get_inode(i_no).ok_or(LookupError::BadInode(i_no))?,
+            FileRef::Inode(inode) => inode,
+        };
+        let layout = FileLayout::load(self, &inode);
+        Ok(Ext4File { fs: self, inode, layout, pos: 0, buffer: (0, Cow::Borrowed(&[])) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_map::BlockRun;
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// A 41000-byte file written sparse by debugfs, then given an unwritten extent
+    /// with `fallocate`: `(0):1162, (4-5):1163-1164, (7-8[u]):1166-1167, (10):1165`
+    fn sparse_layout() -> FileLayout {
+        let run = |logical, physical, len, uninit| BlockRun { logical, physical, len, uninit };
+        let runs = vec![run(0, 1162, 1, false), run(4, 1163, 2, false), run(7, 1166, 2, true), run(10, 1165, 1, false)];
+        FileLayout { size: 41000, block_size: BLOCK_SIZE, contents: FileContents::Mapped(BlockMap::new(runs)) }
+    }
+
+    #[test]
+    fn seek_data_and_hole() {
+        // `lseek` with SEEK_DATA and SEEK_HOLE on the source file, which has no
+        // unwritten extent
+        let expected = [
+            (0, Some(0), Some(4096)),
+            (4095, Some(4095), Some(4096)),
+            (4096, Some(16384), Some(4096)),
+            (10000, Some(16384), Some(10000)),
+            (16384, Some(16384), Some(24576)),
+            (20000, Some(20000), Some(24576)),
+            (24576, Some(40960), Some(24576)),
+            (30000, Some(40960), Some(30000)),
+            (40960, Some(40960), Some(41000)),
+            (40999, Some(40999), Some(41000)),
+            (41000, None, None),
+        ];
+        let layout = sparse_layout();
+        for (offset, data, hole) in expected {
+            assert_eq!((layout.next_data(offset), layout.next_hole(offset)), (data, hole), "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let layout = sparse_layout();
+        assert!(matches!(layout.span(100), Span::Blocks { start: 0, physical: 1162, count: 1 }));
+        assert!(matches!(layout.span(17000), Span::Blocks { start: 16384, physical: 1163, count: 2 }));
+        assert!(matches!(layout.span(5000), Span::Zeros(11384)));
+        assert!(matches!(layout.span(28672), Span::Zeros(12288)));
+        assert!(matches!(layout.span(40999), Span::Blocks { start: 40960, physical: 1165, count: 1 }));
+    }
+
+    #[test]
+    fn inline_past_data() {
+        let layout = FileLayout { size: 100, block_size: BLOCK_SIZE, contents: FileContents::Inline(vec![1; 60]) };
+        assert_eq!((layout.next_data(10), layout.next_hole(10)), (Some(10), Some(60)));
+        assert_eq!((layout.next_data(60), layout.next_hole(70)), (None, Some(70)));
+        assert!(matches!(layout.span(59), Span::Inline([1])));
+        assert!(matches!(layout.span(60), Span::Zeros(40)));
+    }
+
+    #[test]
+    fn seek_positions() {
+        assert_eq!(seek_position(10, 100, SeekFrom::Start(200)).unwrap(), 200);
+        assert_eq!(seek_position(10, 100, SeekFrom::End(-1)).unwrap(), 99);
+        assert_eq!(seek_position(10, 100, SeekFrom::Current(5)).unwrap(), 15);
+        assert!(seek_position(10, 100, SeekFrom::End(-101)).is_err());
+        assert!(seek_position(10, 100, SeekFrom::Current(-11)).is_err());
+    }
+}
@@ -1,16 +1,66 @@
-use crate::defs::{BlockContents, Ext4GroupDesc, Ext4Inode, Ext4SuperBlock, RoCompatFeatures};
-use nom::multi::count;
-use nom::Parser;
+use crate::block_map::BlockMap;
+use crate::cache::{BlockKind, Cache};
+use crate::chain::BufferChainer;
+use crate::defs::{
+    BgFlags, BlockContents, CompatFeatures, Ext4GroupDesc, Ext4Inode, Ext4SuperBlock, IncompatFeatures, InodeFlags,
+    RoCompatFeatures, EXT4_INLINE_DOTDOT_SIZE, EXT4_I_BLOCK_OFFSET, EXT4_MAX_DESC_SIZE, EXT4_MIN_DESC_SIZE_64BIT,
+    EXT4_MIN_INLINE_DATA_SIZE, EXT4_SUPER_MAGIC,
+};
+use crate::device::{sub_bytes, BlockBytes, BlockDevice};
+use crate::journal::BlockOverlay;
+use crate::xattr;
 use nom_derive::Parse;
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
 
-pub struct Ext4Fs<'a> {
+/// A file system read from a `BlockDevice`. The device is owned, borrow it (`&D`
+/// is a device too) to keep it around after the file system.
+pub struct Ext4Fs<D: BlockDevice> {
     super_block: Ext4SuperBlock,
     pub group_descs: Vec<Ext4GroupDesc>,
-    pub file: &'a [u8]
+    device: D,
+    /// blocks read in place of the device's, e.g. replayed from the journal
+    overlay: Option<BlockOverlay>,
+    /// shared cache and the view this file system's parsed items are kept under
+    cache: Option<(Arc<Cache>, u64)>,
 }
 
 pub type Err = String;
 
+/// Inode number of the root directory
+pub const EXT4_ROOT_INO: u64 = 2;
+/// Same limit as the kernel's MAXSYMLINKS
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+/// Bytes `read_to_vec` adds to its buffer per read
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupError {
+    /// A path component does not exist
+    NotFound(String),
+    /// A non-final path component is not a directory
+    NotADirectory(String),
+    /// Too many symlinks were followed while resolving the path
+    SymlinkLoop(String),
+    /// A directory entry points to an inode that cannot be read
+    BadInode(u64),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "{path}: no such file or directory"),
+            Self::NotADirectory(path) => write!(f, "{path}: not a directory"),
+            Self::SymlinkLoop(path) => write!(f, "{path}: too many levels of symbolic links"),
+            Self::BadInode(i_no) => write!(f, "inode {i_no} cannot be read"),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
 fn is_power_of(mut n: u64, b: u64) -> bool {
     match b {
         0 => return n == 0,        
@@ -29,53 +79,542 @@ fn is_power_of(mut n: u64, b: u64) -> bool {
 }
 
 fn has_super_block(is_sparse: bool, index: usize) -> bool {
-    is_sparse || index == 0 || [3, 5, 7].iter().any(|&x| is_power_of(index as u64, x))
+    !is_sparse || index == 0 || [3, 5, 7].iter().any(|&x| is_power_of(index as u64, x))
 } 
 
-impl<'a> Ext4Fs<'a> {
-    pub fn from_file(input: &'a [u8]) -> Result<Self, Err> {
-        let (_, super_block) = Ext4SuperBlock::parse(&input[1024..]).unwrap();
-        let group_descs = Self::parse_group_descs(&super_block, input);
-        Ok(Self { super_block, group_descs, file: input })
+impl<D: BlockDevice> Ext4Fs<D> {
+    pub fn new(device: D) -> Result<Self, Err> {
+        Self::mount(device, None)
+    }
+
+    /// Opens the device with the blocks of `overlay` read in place of its own, the
+    /// device itself is left untouched.
+    pub fn with_overlay(device: D, overlay: BlockOverlay) -> Result<Self, Err> {
+        Self::mount(device, Some(overlay))
+    }
+
+    fn mount(device: D, mut overlay: Option<BlockOverlay>) -> Result<Self, Err> {
+        Self::try_mount(device, &mut overlay)
+    }
+
+    /// `mount` taking the overlay out of `overlay` only when it succeeds
+    fn try_mount(device: D, overlay: &mut Option<BlockOverlay>) -> Result<Self, Err> {
+        let super_block = device
+            .read_bytes(1024, 1024)
+            .and_then(|raw| Ext4SuperBlock::parse(&raw).ok().map(|(_, super_block)| super_block))
+            .ok_or("failed to parse super block")?;
+        if super_block.s_log_block_size % device.block_size() != 0 {
+            return Err(format!(
+                "block size {} is not a multiple of the device's {}",
+                super_block.s_log_block_size,
+                device.block_size()
+            ));
+        }
+        let mut fs = Self { super_block, group_descs: Vec::new(), device, overlay: overlay.take(), cache: None };
+        match fs.load_super_block().and_then(|()| fs.parse_group_descs()) {
+            Ok(group_descs) => {
+                fs.group_descs = group_descs;
+                Ok(fs)
+            }
+            Err(err) => {
+                *overlay = fs.overlay;
+                Err(err)
+            }
+        }
+    }
+
+    /// Reads the super block again through the overlay, which may hold a newer
+    /// copy, and checks it
+    fn load_super_block(&mut self) -> Result<(), Err> {
+        let super_block = self
+            .super_block_bytes()
+            .and_then(|raw| Ext4SuperBlock::parse(&raw).ok().map(|(_, super_block)| super_block))
+            .ok_or("failed to parse super block")?;
+        if super_block.s_magic != EXT4_SUPER_MAGIC {
+            return Err(format!("bad super block magic {:#x}", super_block.s_magic));
+        }
+        let desc_size = super_block.s_desc_size as usize;
+        if super_block.is_64bit()
+            && (!(EXT4_MIN_DESC_SIZE_64BIT..=EXT4_MAX_DESC_SIZE).contains(&desc_size) || !desc_size.is_power_of_two())
+        {
+            return Err(format!("bad group descriptor size {desc_size}"));
+        }
+        self.super_block = super_block;
+        Ok(())
+    }
+
+    pub fn super_block(&self) -> &Ext4SuperBlock {
+        &self.super_block
+    }
+
+    pub fn overlay(&self) -> Option<&BlockOverlay> {
+        self.overlay.as_ref()
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Keeps raw metadata blocks, parsed inodes and block maps in `cache` from now
+    /// on. The cache may be shared with other file systems reading the same device.
+    pub fn set_cache(&mut self, cache: Arc<Cache>) {
+        let view = cache.new_view();
+        self.cache = Some((cache, view));
+    }
+
+    pub fn cache(&self) -> Option<&Arc<Cache>> {
+        self.cache.as_ref().map(|(cache, _)| cache)
+    }
+
+    /// Gives back the device and the overlay
+    pub fn into_parts(self) -> (D, Option<BlockOverlay>) {
+        (self.device, self.overlay)
+    }
+
+    /// Runs `f` on the file system read through `overlay`, which `f` may change
+    /// through `overlay_mut`. The overlay is handed back afterwards, also when the
+    /// file system cannot be mounted.
+    pub(crate) fn lend_overlay<T>(device: D, overlay: &mut BlockOverlay, f: impl FnOnce(&mut Self) -> T) -> Result<T, Err> {
+        let mut lent = Some(mem::take(overlay));
+        let mut fs = Self::try_mount(device, &mut lent).inspect_err(|_| *overlay = lent.take().unwrap_or_default())?;
+        let result = f(&mut fs);
+        *overlay = fs.overlay.unwrap_or_default();
+        Ok(result)
+    }
+
+    /// The overlay, for replays building it up through the file system they read.
+    /// Super block and group descriptors parsed at mount are not updated.
+    pub(crate) fn overlay_mut(&mut self) -> &mut BlockOverlay {
+        self.overlay.get_or_insert_with(BlockOverlay::default)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.super_block.s_log_block_size
+    }
+
+    /// Raw on-disk bytes of the super block
+    pub fn super_block_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        let block_size = self.block_size();
+        let start = (1024 % block_size) as usize;
+        self.get_block(1024 / block_size)?.slice(start..start + 1024).map(BlockBytes::into_cow)
+    }
+
+    /// Block `block` of the file system, from the overlay if it replaces it.
+    /// Borrowed when the device is held in memory, read from it or shared with the
+    /// cache otherwise.
+    pub fn get_block(&self, block: u64) -> Option<BlockBytes<'_>> {
+        self.get_block_as(block, BlockKind::Metadata)
+    }
+
+    /// `get_block` for a block read as `kind`, which only matters to the cache
+    /// statistics
+    pub fn get_block_as(&self, block: u64, kind: BlockKind) -> Option<BlockBytes<'_>> {
+        match &self.cache {
+            Some((cache, _)) if !self.in_overlay(block) && self.device.as_slice().is_none() => {
+                let data = cache.block(block, kind, || Some(self.device.read_block(block, self.block_size())?.into_owned()))?;
+                Some(data.into())
+            }
+            _ => self.get_data_block(block).map(BlockBytes::from),
+        }
+    }
+
+    /// `get_block` for file data, which is not cached
+    pub fn get_data_block(&self, block: u64) -> Option<Cow<'_, [u8]>> {
+        if let Some(data) = self.overlay.as_ref().and_then(|overlay| overlay.get(block)) {
+            return Some(Cow::Borrowed(data));
+        }
+        self.device.read_block(block, self.block_size())
+    }
+
+    /// `count` contiguous blocks from `first` for file data, read at once unless the
+    /// overlay replaces some of them
+    pub fn read_blocks(&self, first: u64, count: u64) -> Option<Cow<'_, [u8]>> {
+        let block_size = self.block_size();
+        let len = usize::try_from(count.checked_mul(block_size)?).ok()?;
+        let blocks = first..first.checked_add(count)?;
+        if !blocks.clone().any(|block| self.in_overlay(block)) {
+            return self.device.read_bytes(first.checked_mul(block_size)?, len);
+        }
+        let mut data = Vec::with_capacity(len);
+        for block in blocks {
+            data.extend_from_slice(&self.get_data_block(block)?);
+        }
+        Some(Cow::Owned(data))
+    }
+
+    fn in_overlay(&self, block: u64) -> bool {
+        self.overlay.as_ref().is_some_and(|overlay| overlay.get(block).is_some())
+    }
+
+    /// Logical block `logical` of a block-mapped directory
+    pub fn dir_block(&self, dir: &Ext4Inode, logical: u64) -> Option<BlockBytes<'_>> {
+        let run = *dir.block_map(self).find(logical)?;
+        self.get_block_as(run.physical + logical - run.logical, BlockKind::DirBlock)
     }
 
     pub fn is_sparse(&self) -> bool {
         self.super_block.s_feature_ro_compat.contains(RoCompatFeatures::SPARSE_SUPER)
     }
 
-    fn parse_group_descs(super_block: &Ext4SuperBlock, input: &[u8]) -> Vec<Ext4GroupDesc> {
-        let block_size = super_block.s_log_block_size;
-        let block_num = super_block.s_blocks_count_lo;
-        let group_num = super_block.s_blocks_count_lo / super_block.s_blocks_per_group;
-        assert!((block_size * block_num as u64) == input.len() as u64);
+    fn parse_group_descs(&self) -> Result<Vec<Ext4GroupDesc>, Err> {
+        (0..self.super_block.group_count())
+            .map(|group| {
+                self.group_desc_bytes(group)
+                    .and_then(|raw| Ext4GroupDesc::parse_sized(&raw))
+                    .ok_or_else(|| format!("failed to parse group descriptor {group}"))
+            })
+            .collect()
+    }
+
+    /// Raw on-disk bytes of the descriptor of `group`, `desc_size()` long
+    pub fn group_desc_bytes(&self, group: u64) -> Option<Cow<'_, [u8]>> {
+        let desc_size = self.super_block.desc_size();
+        let offset = (group % (self.block_size() / desc_size as u64)) as usize * desc_size;
+        let block = self.get_block(Self::group_desc_location(&self.super_block, group))?;
+        block.slice(offset..offset + desc_size).map(BlockBytes::into_cow)
+    }
 
-        let (_, group_descs) = count(Ext4GroupDesc::parse, group_num as usize).parse(&input[block_size as usize ..]).unwrap();
-        group_descs
+    /// Block holding the descriptor of `group`. Without meta_bg the table follows the
+    /// super block, with it each meta group keeps its descriptors in its first group.
+    fn group_desc_location(super_block: &Ext4SuperBlock, group: u64) -> u64 {
+        let first_data_block = super_block.s_first_data_block as u64;
+        let descs_per_block = super_block.s_log_block_size / super_block.desc_size() as u64;
+        let meta_group = group / descs_per_block;
+        if !super_block.s_feature_incompat.contains(IncompatFeatures::META_BG)
+            || meta_group < super_block.s_first_meta_bg as u64
+        {
+            // the block after the super block's, which is not the first data block
+            // of 1k bigalloc file systems
+            let super_block_block = 1024 / super_block.s_log_block_size;
+            return super_block_block + 1 + meta_group;
+        }
+
+        let first_group = meta_group * descs_per_block;
+        let is_sparse = super_block.s_feature_ro_compat.contains(RoCompatFeatures::SPARSE_SUPER);
+        let mut has_super = has_super_block(is_sparse, first_group as usize) as u64;
+        if super_block.s_log_block_size == 1024 && meta_group == 0 && first_data_block == 0 {
+            has_super += 1;
+        }
+        first_data_block + first_group * super_block.s_blocks_per_group as u64 + has_super
     }
 
     pub fn get_inode(&self, i_no: u64) -> Option<Ext4Inode> {
+        let parse = || {
+            let mut inode = Ext4Inode::parse_sized(&self.get_inode_bytes(i_no)?)?;
+            inode.i_no = i_no;
+            Some(inode)
+        };
+        match &self.cache {
+            Some((cache, view)) => cache.inode(*view, i_no, parse),
+            None => parse(),
+        }
+    }
+
+    /// Block map of `inode` from the cache, built by `build` on a miss
+    pub(crate) fn cached_block_map(&self, inode: &Ext4Inode, build: impl FnOnce() -> BlockMap) -> BlockMap {
+        match &self.cache {
+            Some((cache, view)) => {
+                let extents = inode.i_flags.contains(InodeFlags::EXTENTS);
+                BlockMap::clone(&cache.block_map(*view, inode, extents, build))
+            }
+            None => build(),
+        }
+    }
+
+    /// Raw on-disk bytes of an inode, `s_inode_size` long
+    pub fn get_inode_bytes(&self, i_no: u64) -> Option<Cow<'_, [u8]>> {
+        let (block, offset) = self.get_inode_offset(i_no)?;
+        self.get_block(block)?.slice(offset..offset + self.super_block.s_inode_size as usize).map(BlockBytes::into_cow)
+    }
+
+    /// Raw bytes of an inode whether or not its bitmap bit is set, for looking at
+    /// deleted inodes
+    pub fn get_inode_bytes_unchecked(&self, i_no: u64) -> Option<Cow<'_, [u8]>> {
+        let (block, offset) = self.inode_location(i_no)?;
+        self.get_block(block)?.slice(offset..offset + self.super_block.s_inode_size as usize).map(BlockBytes::into_cow)
+    }
+
+    /// Whether the inode bitmap marks `i_no` as in use
+    pub fn is_inode_used(&self, i_no: u64) -> Option<bool> {
+        let index = i_no.checked_sub(1)?;
+        let inodes_per_group = self.super_block.s_inodes_per_group as u64;
+        let group_desc = self.group_descs.get((index / inodes_per_group) as usize)?;
+        self.get_inode_bit(index % inodes_per_group, group_desc)
+    }
+
+    /// Whether the block bitmap marks `block`, or the cluster holding it, as in use
+    pub fn is_block_used(&self, block: u64) -> Option<bool> {
+        let (group, bit) = self.block_bit(block)?;
+        let bitmap = self.block_bitmap(group)?;
+        Some(bitmap.get((bit / 8) as usize)? >> (bit % 8) & 1 == 1)
+    }
+
+    /// Group of `block` and its bit in the group's block bitmap. Under bigalloc
+    /// each bit stands for a cluster of `cluster_ratio()` blocks.
+    pub(crate) fn block_bit(&self, block: u64) -> Option<(u64, u64)> {
+        let index = block.checked_sub(self.super_block.s_first_data_block as u64)?;
+        let blocks_per_group = self.super_block.s_blocks_per_group as u64;
+        Some((index / blocks_per_group, index % blocks_per_group / self.super_block.cluster_ratio()))
+    }
+
+    /// Block bitmap of `group`. A group flagged `BLOCK_UNINIT` has none on disk, its
+    /// bitmap is built the way the kernel initializes it: the super block backup
+    /// and descriptor blocks, the group's own bitmaps and inode table when they
+    /// lie in the group, and the bits past the end of a short last group. A bitmap
+    /// patched into the overlay by a replay replaces the built one.
+    pub fn block_bitmap(&self, group: u64) -> Option<BlockBytes<'_>> {
+        let group_desc = self.group_descs.get(group as usize)?;
+        if !group_desc.bg_flags.contains(BgFlags::BLOCK_UNINIT) || self.in_overlay(group_desc.block_bitmap()) {
+            return self.get_block(group_desc.block_bitmap());
+        }
+        let super_block = &self.super_block;
+        let block_size = self.block_size();
+        let blocks_per_group = super_block.s_blocks_per_group as u64;
+        let first_block = super_block.s_first_data_block as u64 + group * blocks_per_group;
+        let cluster_ratio = super_block.cluster_ratio();
+        let mut bitmap = vec![0u8; block_size as usize];
+        let mut set = |bit: u64| bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        let mut mark = |block: u64| {
+            if let Some(index) = block.checked_sub(first_block).filter(|&index| index < blocks_per_group) {
+                set(index / cluster_ratio);
+            }
+        };
+
+        let descs_per_block = block_size / super_block.desc_size() as u64;
+        let has_super = has_super_block(self.is_sparse(), group as usize) as u64;
+        let is_meta_bg = super_block.s_feature_incompat.contains(IncompatFeatures::META_BG);
+        let base_blocks = if !is_meta_bg || group < super_block.s_first_meta_bg as u64 * descs_per_block {
+            let gdt_blocks = if is_meta_bg {
+                super_block.s_first_meta_bg as u64
+            } else {
+                self.group_descs.len().div_ceil(descs_per_block as usize) as u64
+            };
+            has_super * (1 + gdt_blocks + super_block.s_reserved_gdt_blocks as u64)
+        } else {
+            let index = group % descs_per_block;
+            has_super + [0, 1, descs_per_block - 1].contains(&index) as u64
+        };
+        (first_block..first_block + base_blocks).for_each(&mut mark);
+        mark(group_desc.block_bitmap());
+        mark(group_desc.inode_bitmap());
+        let inode_table_blocks =
+            (super_block.s_inodes_per_group as u64 * super_block.s_inode_size as u64).div_ceil(block_size);
+        (group_desc.inode_table()..group_desc.inode_table() + inode_table_blocks).for_each(&mut mark);
+        let group_blocks = (super_block.blocks_count() - first_block).min(blocks_per_group);
+        (group_blocks.div_ceil(cluster_ratio)..block_size * 8).for_each(set);
+        Some(BlockBytes::Owned(bitmap))
+    }
+
+    /// Location of an allocated inode, see `inode_location`
+    fn get_inode_offset(&self, i_no: u64) -> Option<(u64, usize)> {
+        if i_no == 0 {
+            return None;
+        }
         let offset_in_block = (i_no - 1) % self.super_block.s_inodes_per_group as u64;
         let block_index = (i_no - 1) / self.super_block.s_inodes_per_group as u64;
         let group_desc = self.group_descs.get(block_index as usize)?;
 
         (self.get_inode_bit(offset_in_block, group_desc)?).then_some(())?;
 
-        let inodetable_start = (group_desc.bg_inode_table_lo as u64) * self.super_block.s_log_block_size;
-        let offset = offset_in_block * self.super_block.s_inode_size as u64;
-        Ext4Inode::parse(&self.file[(inodetable_start + offset) as usize..]).map(
-            |(_, inode)| inode).ok()
+        self.inode_location(i_no)
+    }
+
+    /// Inode table block holding `i_no` and the byte offset of the inode in it,
+    /// whether or not the inode is allocated
+    pub fn inode_location(&self, i_no: u64) -> Option<(u64, usize)> {
+        let index = i_no.checked_sub(1)?;
+        let inodes_per_group = self.super_block.s_inodes_per_group as u64;
+        let group_desc = self.group_descs.get((index / inodes_per_group) as usize)?;
+        let offset = (index % inodes_per_group) * self.super_block.s_inode_size as u64;
+        let block_size = self.block_size();
+        Some((group_desc.inode_table() + offset / block_size, (offset % block_size) as usize))
+    }
+
+    pub fn get_inode_block_contents(&self, inode: &Ext4Inode) -> Option<BlockContents<'_>> {
+        if inode.i_mode.ty.is_dir()
+            && let Some(listing) = self.overlay().and_then(|overlay| overlay.listing(inode.i_no))
+        {
+            return Some(BlockContents::Dentries(BufferChainer::new(vec![Cow::Borrowed(listing)])));
+        }
+        if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            return self.get_inline_contents(inode);
+        }
+        inode.get_i_block_contents(self)
+    }
+
+    /// Target of a fast symlink, taken from the image so it outlives the parsed inode
+    pub(crate) fn fast_symlink_contents(&self, inode: &Ext4Inode) -> Option<BlockContents<'_>> {
+        let raw = self.get_inode_bytes(inode.i_no)?;
+        let target = sub_bytes(raw, EXT4_I_BLOCK_OFFSET..EXT4_I_BLOCK_OFFSET + inode.size() as usize)?;
+        Some(BlockContents::InliedData(target))
+    }
+
+    /// Inline data is the 60 bytes of `i_block` followed by the `system.data` xattr.
+    /// Inline directories start with the parent inode number and have no `.`/`..` entries.
+    fn get_inline_contents(&self, inode: &Ext4Inode) -> Option<BlockContents<'_>> {
+        let mut data = self.inline_data(inode)?;
+        if inode.i_mode.ty.is_dir() {
+            data.drain(..EXT4_INLINE_DOTDOT_SIZE);
+            Some(BlockContents::Dentries(BufferChainer::new(vec![Cow::Owned(data)])))
+        } else {
+            data.truncate(inode.size() as usize);
+            Some(BlockContents::Data(BufferChainer::new(vec![Cow::Owned(data)])))
+        }
+    }
+
+    /// The 60 bytes of `i_block` followed by the value of `system.data`
+    pub(crate) fn inline_data(&self, inode: &Ext4Inode) -> Option<Vec<u8>> {
+        let raw = self.get_inode_bytes(inode.i_no)?;
+        let mut data = raw.get(EXT4_I_BLOCK_OFFSET..EXT4_I_BLOCK_OFFSET + EXT4_MIN_INLINE_DATA_SIZE)?.to_vec();
+        if let Some(value) = xattr::inline_data_value(&raw, inode.i_extra_isize) {
+            data.extend_from_slice(value);
+        }
+        Some(data)
+    }
+
+    /// Parent directory recorded in the header of an inline directory
+    fn inline_dir_parent(&self, inode: &Ext4Inode) -> Option<u64> {
+        let header = self.inline_data(inode)?.get(..EXT4_INLINE_DOTDOT_SIZE)?.try_into().ok()?;
+        Some(u32::from_le_bytes(header) as u64)
+    }
+
+    /// Reads file data starting at byte `offset` into `buf` and returns the number of
+    /// bytes read, which is only short at the end of the file. `None` when a mapped
+    /// block cannot be read.
+    pub fn read_at(&self, inode: &Ext4Inode, offset: u64, buf: &mut [u8]) -> Option<usize> {
+        let end = inode.size().min(offset.saturating_add(buf.len() as u64));
+        if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            let data = self.inline_data(inode).unwrap_or_default();
+            let src = data.get(offset as usize..).unwrap_or_default();
+            let len = (end.saturating_sub(offset) as usize).min(src.len());
+            buf[..len].copy_from_slice(&src[..len]);
+            return Some(len);
+        }
+
+        let block_size = self.super_block.s_log_block_size;
+        let block_map = inode.block_map(self);
+        let mut pos = offset;
+        while pos < end {
+            let logical = pos / block_size;
+            let in_block = pos % block_size;
+            let len = (block_size - in_block).min(end - pos) as usize;
+            let dst = &mut buf[(pos - offset) as usize..][..len];
+            match block_map.find(logical) {
+                Some(run) if !run.uninit => {
+                    let block = self.get_data_block(run.physical + logical - run.logical);
+                    let src = block.as_deref().and_then(|block| block.get(in_block as usize..in_block as usize + len))?;
+                    dst.copy_from_slice(src);
+                }
+                _ => dst.fill(0),
+            }
+            pos += len as u64;
+        }
+        Some((pos.max(offset) - offset) as usize)
+    }
+
+    /// Reads the whole file into memory, `None` when a mapped block cannot be read.
+    /// The buffer grows with each chunk read rather than trusting `i_size` up front.
+    pub fn read_to_vec(&self, inode: &Ext4Inode) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        loop {
+            let start = buf.len();
+            buf.resize(start + READ_CHUNK_SIZE, 0);
+            let len = self.read_at(inode, start as u64, &mut buf[start..])?;
+            buf.truncate(start + len);
+            if len < READ_CHUNK_SIZE {
+                return Some(buf);
+            }
+        }
+    }
+
+    /// Resolves an absolute path (relative paths start at the root too) to its inode.
+    /// Symlinks in intermediate components are always followed, the final component
+    /// is only followed when `follow_symlinks` is set.
+    pub fn lookup(&self, path: &str, follow_symlinks: bool) -> Result<(u64, Ext4Inode), LookupError> {
+        let mut follows = 0;
+        self.lookup_at(EXT4_ROOT_INO, path, follow_symlinks, &mut follows)
+    }
+
+    fn lookup_at(&self, dir: u64, path: &str, follow_symlinks: bool, follows: &mut usize) -> Result<(u64, Ext4Inode), LookupError> {
+        let mut current = if path.starts_with('/') { EXT4_ROOT_INO } else { dir };
+        let mut inode = self.get_inode(current).ok_or(LookupError::BadInode(current))?;
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let mut walked = String::new();
+
+        for (index, name) in components.iter().enumerate() {
+            if !inode.i_mode.ty.is_dir() {
+                return Err(LookupError::NotADirectory(walked));
+            }
+            walked.push('/');
+            walked.push_str(name);
+            if *name == "." {
+                continue;
+            }
+
+            let parent = current;
+            current = self.find_entry(&inode, name.as_bytes()).ok_or_else(|| LookupError::NotFound(walked.clone()))?;
+            inode = self.get_inode(current).ok_or(LookupError::BadInode(current))?;
+
+            let is_last = index + 1 == components.len();
+            if inode.i_mode.ty.is_symlink() && (!is_last || follow_symlinks) {
+                *follows += 1;
+                if *follows > MAX_SYMLINK_FOLLOWS {
+                    return Err(LookupError::SymlinkLoop(walked));
+                }
+                let target = self.read_link(&inode).ok_or(LookupError::BadInode(current))?;
+                let target = String::from_utf8_lossy(&target).into_owned();
+                (current, inode) = self.lookup_at(parent, &target, true, follows)?;
+            }
+        }
+        Ok((current, inode))
+    }
+
+    /// Returns the inode number of `name` in the directory `dir`.
+    pub fn find_entry(&self, dir: &Ext4Inode, name: &[u8]) -> Option<u64> {
+        if let Some(found) = self.overlay().and_then(|overlay| overlay.dir_entry(dir.i_no, name)) {
+            return found;
+        }
+        if dir.i_flags.contains(InodeFlags::INLINE_DATA) {
+            match name {
+                b"." => return Some(dir.i_no),
+                b".." => return self.inline_dir_parent(dir),
+                _ => {}
+            }
+        }
+        if dir.i_flags.contains(InodeFlags::INDEX)
+            && self.super_block.s_feature_compat.contains(CompatFeatures::DIR_INDEX)
+            && let Ok(found) = self.htree_find_entry(dir, name)
+        {
+            return found;
+        }
+        match self.get_inode_block_contents(dir)? {
+            BlockContents::Dentries(entries) => entries
+                .filter(|d_entry| d_entry.inode != 0)
+                .find(|d_entry| d_entry.name_bytes() == name)
+                .map(|d_entry| d_entry.inode as u64),
+            _ => None,
+        }
     }
 
-    pub fn get_inode_block_contents(&self, inode: &Ext4Inode) -> Option<BlockContents> {
-        inode.get_i_block_contents(&self.file, self.super_block.s_log_block_size as usize)
+    /// Target of a symlink, exactly `i_size` bytes long.
+    pub fn read_link(&self, inode: &Ext4Inode) -> Option<Vec<u8>> {
+        if !inode.i_mode.ty.is_symlink() {
+            return None;
+        }
+        let target = match self.get_inode_block_contents(inode)? {
+            BlockContents::InliedData(data) => data.to_vec(),
+            BlockContents::Data(chain) => chain.read_all(),
+            BlockContents::Dentries(_) | BlockContents::Special(_) => return None,
+        };
+        (target.len() as u64 == inode.size()).then_some(target)
     }
 
     fn get_inode_bit(&self, offset_in_block: u64, group_desc: &Ext4GroupDesc) -> Option<bool> {
-        let bitmap_start = (group_desc.bg_inode_bitmap_lo as usize) * self.super_block.s_log_block_size as usize;
+        let bitmap = self.get_block(group_desc.inode_bitmap())?;
 
         let inode_bitgroup_index = offset_in_block / 8;
         let inode_bit_index = offset_in_block % 8;
-        let inode_bit_group = &self.file.get(bitmap_start + inode_bitgroup_index as usize)?;
+        let inode_bit_group = &bitmap.get(inode_bitgroup_index as usize)?;
         Some(((*inode_bit_group) >> inode_bit_index) & 0x1 == 0x1)
     }
 }
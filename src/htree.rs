@@ -0,0 +1,334 @@
+use crate::chain::BufferChainer;
+use crate::defs::{
+    Ext4DirEntry, Ext4DxCountLimit, Ext4DxEntry, Ext4DxRootInfo, Ext4Inode, IncompatFeatures,
+};
+use crate::device::BlockDevice;
+use crate::fs_parser::{Err, Ext4Fs};
+use nom_derive::Parse;
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// `s_flags` bit telling that names hash as signed chars
+pub const EXT2_FLAGS_SIGNED_HASH: u32 = 0x0001;
+/// `s_flags` bit telling that names hash as unsigned chars
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+const EXT4_HTREE_EOF_32BIT: u32 = 0x7fff_ffff;
+/// "." and ".." entries in front of the dx_root info
+pub(crate) const DX_ROOT_INFO_OFFSET: usize = 24;
+/// fake dirent in front of the entries of a dx_node
+pub(crate) const DX_NODE_ENTRIES_OFFSET: usize = 8;
+pub(crate) const DX_ENTRY_SIZE: usize = 8;
+
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+/// Returns the major and minor hash of `name`, or an error for a hash version
+/// this crate does not know.
+pub fn dx_hash(name: &[u8], hash_version: u8, seed: &[u32; 4]) -> Result<(u32, u32), Err> {
+    let mut buf = if seed.iter().any(|&word| word != 0) { *seed } else { DEFAULT_SEED };
+    let (hash, minor_hash) = match hash_version {
+        DX_HASH_LEGACY => (dx_hack_hash(name, true), 0),
+        DX_HASH_LEGACY_UNSIGNED => (dx_hack_hash(name, false), 0),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let signed = hash_version == DX_HASH_HALF_MD4;
+            let mut input = [0u32; 8];
+            for start in (0..name.len()).step_by(32) {
+                str2hashbuf(&name[start..], signed, &mut input);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let signed = hash_version == DX_HASH_TEA;
+            let mut input = [0u32; 4];
+            for start in (0..name.len()).step_by(16) {
+                str2hashbuf(&name[start..], signed, &mut input);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+        _ => return Err(format!("unknown dx hash version {hash_version}")),
+    };
+    let mut hash = hash & !1;
+    if hash == EXT4_HTREE_EOF_32BIT << 1 {
+        hash = (EXT4_HTREE_EOF_32BIT - 1) << 1;
+    }
+    Ok((hash, minor_hash))
+}
+
+fn char_value(byte: u8, signed: bool) -> u32 {
+    if signed { byte as i8 as i32 as u32 } else { byte as u32 }
+}
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2du32, 0x37ab_e8f9u32);
+    for &byte in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(byte, signed).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Packs the next `4 * out.len()` bytes of `msg` into words, padding with the length.
+fn str2hashbuf(msg: &[u8], signed: bool, out: &mut [u32]) {
+    let len = msg.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+
+    let mut val = pad;
+    let mut words = 0;
+    for (index, &byte) in msg.iter().take(out.len() * 4).enumerate() {
+        val = char_value(byte, signed).wrapping_add(val << 8);
+        if index % 4 == 3 {
+            out[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+    if words < out.len() {
+        out[words] = val;
+        words += 1;
+    }
+    out[words..].fill(pad);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9E37_79B9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K1: u32 = 0;
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let round = |func: &dyn Fn(u32, u32, u32) -> u32, a: &mut u32, b: u32, c: u32, d: u32, x: u32, s: u32| {
+        *a = a.wrapping_add(func(b, c, d)).wrapping_add(x).rotate_left(s);
+    };
+
+    let [mut a, mut b, mut c, mut d] = *buf;
+
+    round(&f, &mut a, b, c, d, input[0].wrapping_add(K1), 3);
+    round(&f, &mut d, a, b, c, input[1].wrapping_add(K1), 7);
+    round(&f, &mut c, d, a, b, input[2].wrapping_add(K1), 11);
+    round(&f, &mut b, c, d, a, input[3].wrapping_add(K1), 19);
+    round(&f, &mut a, b, c, d, input[4].wrapping_add(K1), 3);
+    round(&f, &mut d, a, b, c, input[5].wrapping_add(K1), 7);
+    round(&f, &mut c, d, a, b, input[6].wrapping_add(K1), 11);
+    round(&f, &mut b, c, d, a, input[7].wrapping_add(K1), 19);
+
+    round(&g, &mut a, b, c, d, input[1].wrapping_add(K2), 3);
+    round(&g, &mut d, a, b, c, input[3].wrapping_add(K2), 5);
+    round(&g, &mut c, d, a, b, input[5].wrapping_add(K2), 9);
+    round(&g, &mut b, c, d, a, input[7].wrapping_add(K2), 13);
+    round(&g, &mut a, b, c, d, input[0].wrapping_add(K2), 3);
+    round(&g, &mut d, a, b, c, input[2].wrapping_add(K2), 5);
+    round(&g, &mut c, d, a, b, input[4].wrapping_add(K2), 9);
+    round(&g, &mut b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round(&h, &mut a, b, c, d, input[3].wrapping_add(K3), 3);
+    round(&h, &mut d, a, b, c, input[7].wrapping_add(K3), 9);
+    round(&h, &mut c, d, a, b, input[2].wrapping_add(K3), 11);
+    round(&h, &mut b, c, d, a, input[6].wrapping_add(K3), 15);
+    round(&h, &mut a, b, c, d, input[1].wrapping_add(K3), 3);
+    round(&h, &mut d, a, b, c, input[5].wrapping_add(K3), 9);
+    round(&h, &mut c, d, a, b, input[0].wrapping_add(K3), 11);
+    round(&h, &mut b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// One level of the path from the dx_root down to a leaf
+struct DxFrame {
+    entries: Vec<Ext4DxEntry>,
+    at: usize,
+}
+
+impl DxFrame {
+    /// Parses the count/limit header and entries starting at `input`. The first
+    /// entry's hash slot holds the count/limit and is treated as hash 0.
+    fn parse(input: &[u8]) -> Result<Self, Err> {
+        let (_, count_limit) = Ext4DxCountLimit::parse(input).map_err(|_| "truncated dx node")?;
+        let count = count_limit.count as usize;
+        if count == 0 || count > count_limit.limit as usize || count * DX_ENTRY_SIZE > input.len() {
+            return Err(format!("bad dx count {}/{}", count_limit.count, count_limit.limit));
+        }
+        let entries = input[..count * DX_ENTRY_SIZE]
+            .chunks_exact(DX_ENTRY_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let (_, mut entry) = Ext4DxEntry::parse(chunk).unwrap();
+                if index == 0 {
+                    entry.hash = 0;
+                }
+                entry
+            })
+            .collect();
+        Ok(Self { entries, at: 0 })
+    }
+
+    /// Positions on the last entry whose hash is not above `hash`.
+    fn seek(&mut self, hash: u32) {
+        self.at = self.entries[1..].partition_point(|entry| entry.hash <= hash);
+    }
+
+    fn block(&self) -> u64 {
+        self.entries[self.at].block as u64
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Looks `name` up through the hash tree of an indexed directory. An error means
+    /// the index cannot be used and the caller should fall back to a linear scan.
+    pub fn htree_find_entry(&self, dir: &Ext4Inode, name: &[u8]) -> Result<Option<u64>, Err> {
+        let super_block = self.super_block();
+        let root = self.dir_block(dir, 0).ok_or("cannot read dx root")?;
+        if name == b"." || name == b".." {
+            // both live in front of the dx_root info rather than in a leaf
+            let found = BufferChainer::<Ext4DirEntry>::new(vec![root])
+                .take(2)
+                .find(|d_entry| d_entry.name_bytes() == name);
+            return Ok(found.map(|d_entry| d_entry.inode as u64));
+        }
+        let (rest, info) = root
+            .get(DX_ROOT_INFO_OFFSET..)
+            .and_then(|rest| Ext4DxRootInfo::parse(rest).ok())
+            .ok_or("truncated dx root")?;
+        let max_levels = if super_block.s_feature_incompat.contains(IncompatFeatures::LARGEDIR) { 3 } else { 2 };
+        if info.reserved_zero != 0 || info.indirect_levels >= max_levels {
+            return Err(format!("unsupported dx root, {} levels", info.indirect_levels));
+        }
+        // the kernel's dx_probe rejects any other length too
+        if info.info_length as usize != size_of::<Ext4DxRootInfo>() {
+            return Err(format!("bad dx root info length {}", info.info_length));
+        }
+
+        let mut hash_version = info.hash_version;
+        if hash_version <= DX_HASH_TEA && super_block.s_flags & EXT2_FLAGS_UNSIGNED_HASH != 0 {
+            hash_version += 3;
+        }
+        let (hash, _) = dx_hash(name, hash_version, &super_block.s_hash_seed)?;
+        let mut frames = vec![DxFrame::parse(rest)?];
+        frames[0].seek(hash);
+        for _ in 0..info.indirect_levels {
+            let frame = self.dx_node(dir, frames.last().unwrap().block())?;
+            frames.push(frame);
+            frames.last_mut().unwrap().seek(hash);
+        }
+
+        loop {
+            let leaf = self.dir_block(dir, frames.last().unwrap().block()).ok_or("cannot read dx leaf")?;
+            let found = BufferChainer::<Ext4DirEntry>::new(vec![leaf])
+                .filter(|d_entry| d_entry.inode != 0)
+                .find(|d_entry| d_entry.name_bytes() == name);
+            if let Some(d_entry) = found {
+                return Ok(Some(d_entry.inode as u64));
+            }
+            if !self.dx_next_leaf(dir, &mut frames, hash)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Steps to the next leaf when the following block continues a hash collision.
+    fn dx_next_leaf(&self, dir: &Ext4Inode, frames: &mut Vec<DxFrame>, hash: u32) -> Result<bool, Err> {
+        let levels = frames.len();
+        while let Some(frame) = frames.last_mut() {
+            frame.at += 1;
+            if frame.at < frame.entries.len() {
+                break;
+            }
+            frames.pop();
+        }
+        let Some(frame) = frames.last() else {
+            return Ok(false);
+        };
+        if frame.entries[frame.at].hash & !1 != hash {
+            return Ok(false);
+        }
+        while frames.len() < levels {
+            let frame = self.dx_node(dir, frames.last().unwrap().block())?;
+            frames.push(frame);
+        }
+        Ok(true)
+    }
+
+    fn dx_node(&self, dir: &Ext4Inode, block: u64) -> Result<DxFrame, Err> {
+        let node = self.dir_block(dir, block).ok_or("cannot read dx node")?;
+        DxFrame::parse(node.get(DX_NODE_ENTRIES_OFFSET..).ok_or("truncated dx node")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `0b7a6e0e-6f3d-4b5a-9e4c-2f1d8c7b6a59` as stored in `s_hash_seed`
+    const SEED: [u32; 4] = [0x0e6e_7a0b, 0x5a4b_3d6f, 0x1d2f_4c9e, 0x596a_7b8c];
+    const LONG_NAME: &[u8] = b"a_rather_long_file_name_spanning_more_than_thirty_two_bytes.txt";
+    const CAFE: &[u8] = "caf\u{e9}".as_bytes();
+
+    // expected values from debugfs `dx_hash -h <version> -s <seed> <name>`
+
+    #[test]
+    fn legacy_hash() {
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_LEGACY, &SEED), Ok((0x5e2a_ba24, 0)));
+        assert_eq!(dx_hash(LONG_NAME, DX_HASH_LEGACY, &SEED), Ok((0x77e8_69cc, 0)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_LEGACY, &SEED), Ok((0x96ca_5a2c, 0)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_LEGACY_UNSIGNED, &SEED), Ok((0x6dde_4230, 0)));
+    }
+
+    #[test]
+    fn half_md4_hash() {
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_HALF_MD4, &SEED), Ok((0xdd22_b730, 0xf992_86a6)));
+        assert_eq!(dx_hash(LONG_NAME, DX_HASH_HALF_MD4, &SEED), Ok((0x7cb5_e4fc, 0x3ad2_1418)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_HALF_MD4, &SEED), Ok((0x0379_e202, 0x3bb1_3528)));
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_HALF_MD4_UNSIGNED, &SEED), Ok((0xdd22_b730, 0xf992_86a6)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_HALF_MD4_UNSIGNED, &SEED), Ok((0x4685_bb44, 0xc6f0_1f86)));
+    }
+
+    #[test]
+    fn tea_hash() {
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_TEA, &SEED), Ok((0x1762_9fd8, 0xb3f4_5a4b)));
+        assert_eq!(dx_hash(LONG_NAME, DX_HASH_TEA, &SEED), Ok((0x101f_bd6a, 0x6303_18dc)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_TEA, &SEED), Ok((0x17df_a33e, 0x2031_6985)));
+        assert_eq!(dx_hash(CAFE, DX_HASH_TEA_UNSIGNED, &SEED), Ok((0xe9a6_2e62, 0x9c13_0dd6)));
+    }
+
+    #[test]
+    fn zero_seed_uses_default() {
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_HALF_MD4, &[0; 4]), Ok((0x591d_e422, 0x6ffc_56e0)));
+        assert_eq!(dx_hash(b"lost+found", DX_HASH_TEA, &[0; 4]), Ok((0x2dbf_9e80, 0xbfeb_ee4f)));
+    }
+
+    #[test]
+    fn unknown_hash_version() {
+        assert!(dx_hash(b"lost+found", 6, &SEED).is_err());
+    }
+}
@@ -0,0 +1,699 @@
+use crate::block_map::BlockMap;
+use crate::csum::crc32c;
+use crate::defs::{
+    CompatFeatures, Ext4Inode, IncompatFeatures, InodeFlags, JournalCommitBlock, JournalHeader, JournalIncompatFeatures,
+    JournalSuperBlock, JournalTagFlags, EXT4_N_BLOCKS, JBD2_COMMIT_BLOCK, JBD2_DESCRIPTOR_BLOCK, JBD2_MAGIC_NUMBER,
+    JBD2_REVOKE_BLOCK, JBD2_SUPERBLOCK_V1, JBD2_SUPERBLOCK_V2,
+};
+use crate::device::BlockDevice;
+use crate::fast_commit::RangeEdit;
+use crate::fs_parser::{Err, Ext4Fs};
+use crate::metadata::Timestamp;
+use nom_derive::Parse;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+/// `s_jnl_backup_type` telling that `s_jnl_blocks` holds a copy of the journal's `i_block`
+const EXT3_JNL_BACKUP_BLOCKS: u8 = 1;
+/// Size of the journal header, revoke blocks add a 4-byte `r_count`
+const JBD2_HEADER_SIZE: usize = 12;
+const JBD2_REVOKE_HEADER_SIZE: usize = 16;
+/// Checksum closing descriptor and revoke blocks under csum v2/v3
+const JBD2_BLOCK_TAIL_SIZE: usize = 4;
+const JBD2_UUID_SIZE: usize = 16;
+/// Fast commit blocks reserved when `s_num_fc_blks` is 0
+pub const JBD2_DEFAULT_FAST_COMMIT_BLOCKS: u32 = 256;
+
+/// The jbd2 log kept in the journal inode
+pub struct Journal<'a> {
+    pub super_block: JournalSuperBlock,
+    device: &'a dyn BlockDevice,
+    block_map: BlockMap,
+    block_size: u64,
+    csum_seed: u32,
+}
+
+/// A copy of a file system block logged by a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedBlock {
+    /// file system block the copy is written back to
+    pub fs_block: u64,
+    /// journal block holding the copy
+    pub journal_block: u64,
+    /// the block started with the journal magic, which the copy has zeroed
+    pub escaped: bool,
+    /// tag checksum matches the copy, always true without csum v2/v3
+    pub csum_ok: bool,
+}
+
+/// A committed transaction, in log order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub sequence: u32,
+    pub commit_time: Timestamp,
+    /// journal blocks of the first descriptor and of the commit record
+    pub start: u64,
+    pub commit_block: u64,
+    pub blocks: Vec<LoggedBlock>,
+    /// file system blocks revoked by this transaction
+    pub revoked: Vec<u64>,
+}
+
+/// File system blocks read in place of the image's own, along with the changes
+/// fast commits replay without writing blocks
+#[derive(Debug, Clone, Default)]
+pub struct BlockOverlay {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    /// changes to the block map of each inode, oldest first
+    pub(crate) range_edits: BTreeMap<u64, Vec<RangeEdit>>,
+    /// entries added to (`Some`) or removed from (`None`) a directory, by directory and name
+    pub(crate) dir_entries: BTreeMap<(u64, Vec<u8>), Option<u64>>,
+    /// raw entries of the directories listed in `dir_entries`, in place of their blocks
+    pub(crate) listings: BTreeMap<u64, Vec<u8>>,
+}
+
+impl BlockOverlay {
+    /// Overlay bringing a device to the state the kernel leaves it in after
+    /// recovery, empty unless the file system is flagged as needing recovery.
+    /// Fast commits made after the last transaction are replayed on top of it.
+    pub fn recover<D: BlockDevice + ?Sized>(device: &D) -> Result<Self, Err> {
+        let fs = Ext4Fs::new(device)?;
+        if !fs.super_block().s_feature_incompat.contains(IncompatFeatures::RECOVER) {
+            return Ok(Self::default());
+        }
+        let journal = fs.journal()?;
+        let mut overlay = journal.replay();
+        if !journal.is_clean() && journal.has_incompat(JournalIncompatFeatures::FAST_COMMIT) {
+            overlay.replay_fast_commits(device, &journal.fast_commit_tags(journal.next_sequence()))?;
+        }
+        Ok(overlay)
+    }
+
+    /// Overlay showing the metadata as of journal transaction `sequence`, see
+    /// `Journal::as_of`.
+    pub fn as_of<D: BlockDevice + ?Sized>(device: &D, sequence: u32) -> Result<Self, Err> {
+        Ext4Fs::new(device)?.journal()?.as_of(sequence)
+    }
+
+    pub fn get(&self, block: u64) -> Option<&[u8]> {
+        self.blocks.get(&block).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, block: u64, data: Vec<u8>) {
+        self.blocks.insert(block, data);
+    }
+
+    /// Replaced blocks in ascending order
+    pub fn blocks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.blocks.keys().copied()
+    }
+
+    /// Number of replaced blocks
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.range_edits.is_empty() && self.dir_entries.is_empty()
+    }
+
+    /// Changes to the block map of inode `i_no`, oldest first
+    pub fn range_edits(&self, i_no: u64) -> &[RangeEdit] {
+        self.range_edits.get(&i_no).map_or(&[], Vec::as_slice)
+    }
+
+    /// Inode `name` now points to in `dir`, `Some(None)` once removed and `None`
+    /// when the entry did not change
+    pub fn dir_entry(&self, dir: u64, name: &[u8]) -> Option<Option<u64>> {
+        self.dir_entries.get(&(dir, name.to_vec())).copied()
+    }
+
+    /// Raw entries of a directory whose entries changed
+    pub fn listing(&self, dir: u64) -> Option<&[u8]> {
+        self.listings.get(&dir).map(Vec::as_slice)
+    }
+}
+
+/// An inode as logged by one transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeVersion {
+    pub sequence: u32,
+    pub commit_time: Timestamp,
+    /// journal block holding the copy of the inode table block
+    pub journal_block: u64,
+    /// tag checksum of the copy matched
+    pub csum_ok: bool,
+    pub inode: Ext4Inode,
+}
+
+/// Transaction IDs wrap, `a` is newer when the distance to it is below half the range
+fn tid_gt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+impl<'a> Journal<'a> {
+    pub fn new(device: &'a dyn BlockDevice, block_map: BlockMap, block_size: u64) -> Result<Self, Err> {
+        let mut super_block = map_block(device, &block_map, block_size, 0)
+            .and_then(|block| JournalSuperBlock::parse(&block).ok().map(|(_, super_block)| super_block))
+            .ok_or("cannot read journal super block")?;
+        let header = super_block.s_header;
+        if header.h_magic != JBD2_MAGIC_NUMBER
+            || !matches!(header.h_blocktype, JBD2_SUPERBLOCK_V1 | JBD2_SUPERBLOCK_V2)
+        {
+            return Err(format!("bad journal super block {:#x}/{}", header.h_magic, header.h_blocktype));
+        }
+        if super_block.s_blocksize as u64 != block_size {
+            return Err(format!("journal block size {} differs from {block_size}", super_block.s_blocksize));
+        }
+        if header.h_blocktype == JBD2_SUPERBLOCK_V1 {
+            // v1 super blocks end before the feature fields
+            super_block.s_feature_compat = Default::default();
+            super_block.s_feature_incompat = Default::default();
+            super_block.s_feature_ro_compat = 0;
+            super_block.s_num_fc_blks = 0;
+        }
+        let csum_seed = crc32c(!0, &super_block.s_uuid);
+        let journal = Self { super_block, device, block_map, block_size, csum_seed };
+        // the checks of jbd2's journal_get_superblock, which keep `last()` and `wrap()` in range
+        if journal.super_block.s_maxlen as u64 <= journal.fast_commit_blocks() {
+            return Err(format!(
+                "journal of {} blocks cannot hold {} fast commit blocks",
+                journal.super_block.s_maxlen,
+                journal.fast_commit_blocks()
+            ));
+        }
+        if journal.first() == 0 || journal.first() >= journal.last() {
+            return Err(format!("journal log starts at {} outside of 1..{}", journal.first(), journal.last()));
+        }
+        Ok(journal)
+    }
+
+    pub fn has_incompat(&self, feature: JournalIncompatFeatures) -> bool {
+        self.super_block.s_feature_incompat.contains(feature)
+    }
+
+    /// Checksum v2 and v3 both protect descriptor, revoke and commit blocks
+    pub fn has_csum_v2v3(&self) -> bool {
+        self.has_incompat(JournalIncompatFeatures::CSUM_V2) || self.has_incompat(JournalIncompatFeatures::CSUM_V3)
+    }
+
+    /// No transaction is waiting to be replayed
+    pub fn is_clean(&self) -> bool {
+        self.super_block.s_start == 0
+    }
+
+    /// First block of the circular log
+    pub fn first(&self) -> u64 {
+        self.super_block.s_first as u64
+    }
+
+    /// End of the circular log, the fast commit area follows it
+    pub fn last(&self) -> u64 {
+        self.super_block.s_maxlen as u64 - self.fast_commit_blocks()
+    }
+
+    /// Number of blocks reserved for fast commits at the end of the journal
+    pub fn fast_commit_blocks(&self) -> u64 {
+        if !self.has_incompat(JournalIncompatFeatures::FAST_COMMIT) {
+            return 0;
+        }
+        match self.super_block.s_num_fc_blks {
+            0 => JBD2_DEFAULT_FAST_COMMIT_BLOCKS as u64,
+            blocks => blocks as u64,
+        }
+    }
+
+    /// Journal block `block`, counted from the start of the journal inode
+    pub fn block(&self, block: u64) -> Option<Cow<'a, [u8]>> {
+        map_block(self.device, &self.block_map, self.block_size, block)
+    }
+
+    /// Contents of a logged block as they go back to the file system, with the
+    /// magic number restored on escaped blocks.
+    pub fn logged_data(&self, logged: &LoggedBlock) -> Option<Cow<'a, [u8]>> {
+        let data = self.block(logged.journal_block)?;
+        if !logged.escaped {
+            return Some(data);
+        }
+        let mut data = data.into_owned();
+        data[..4].copy_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+        Some(Cow::Owned(data))
+    }
+
+    /// Committed transactions from the start of the log, as recovery would see them.
+    /// A clean journal has no start, its walk begins at `s_first` with whatever
+    /// transaction was last written there, which still shows the recent history.
+    pub fn transactions(&self) -> Transactions<'_, 'a> {
+        let (block, sequence) = if self.is_clean() {
+            let header = self.block(self.first()).and_then(|data| JournalHeader::parse(&data).ok().map(|(_, header)| header));
+            (self.first(), header.map_or(0, |header| header.h_sequence))
+        } else {
+            (self.super_block.s_start as u64, self.super_block.s_sequence)
+        };
+        Transactions { journal: self, block, sequence, walked: 0 }
+    }
+
+    /// Replays the log the way recovery does: later copies of a block win, and a
+    /// block revoked by a transaction is not replayed from it or any older one.
+    /// Copies failing their tag checksum are skipped like the kernel skips them.
+    /// Fast commits need the file system, `BlockOverlay::recover` replays them.
+    pub fn replay(&self) -> BlockOverlay {
+        if self.is_clean() {
+            return BlockOverlay::default();
+        }
+        self.overlay(&self.transactions().collect::<Vec<_>>())
+    }
+
+    /// Replays the log up to and including transaction `sequence`, giving the
+    /// metadata as it stood when that transaction committed. Blocks only logged by
+    /// later transactions keep their current contents.
+    pub fn as_of(&self, sequence: u32) -> Result<BlockOverlay, Err> {
+        let mut transactions = Vec::new();
+        for transaction in self.transactions() {
+            let done = transaction.sequence == sequence;
+            transactions.push(transaction);
+            if done {
+                return Ok(self.overlay(&transactions));
+            }
+        }
+        Err(format!("transaction {sequence} is not in the journal"))
+    }
+
+    fn overlay(&self, transactions: &[Transaction]) -> BlockOverlay {
+        let mut overlay = BlockOverlay::default();
+        let mut revoked = HashMap::new();
+        for transaction in transactions {
+            for &block in &transaction.revoked {
+                revoked.insert(block, transaction.sequence);
+            }
+        }
+        for transaction in transactions {
+            for logged in &transaction.blocks {
+                let is_revoked = revoked.get(&logged.fs_block).is_some_and(|&sequence| !tid_gt(transaction.sequence, sequence));
+                if is_revoked || !logged.csum_ok {
+                    continue;
+                }
+                if let Some(data) = self.logged_data(logged) {
+                    overlay.insert(logged.fs_block, data.into_owned());
+                }
+            }
+        }
+        overlay
+    }
+
+    fn wrap(&self, block: u64) -> u64 {
+        if block >= self.last() { block - (self.last() - self.first()) } else { block }
+    }
+
+    /// Size of a descriptor tag, not counting the UUID that may follow it
+    fn tag_bytes(&self) -> usize {
+        if self.has_incompat(JournalIncompatFeatures::CSUM_V3) {
+            return 16;
+        }
+        let mut size = 12;
+        if self.has_incompat(JournalIncompatFeatures::CSUM_V2) {
+            size += 2;
+        }
+        if !self.has_incompat(JournalIncompatFeatures::_64BIT) {
+            size -= 4;
+        }
+        size
+    }
+
+    /// Descriptor and revoke blocks are checksummed with their tail zeroed
+    fn block_tail_csum_ok(&self, data: &[u8]) -> bool {
+        if !self.has_csum_v2v3() {
+            return true;
+        }
+        let (body, tail) = data.split_at(data.len() - JBD2_BLOCK_TAIL_SIZE);
+        let csum = crc32c(crc32c(self.csum_seed, body), &[0; JBD2_BLOCK_TAIL_SIZE]);
+        tail == csum.to_be_bytes()
+    }
+
+    fn commit_csum_ok(&self, data: &[u8], commit: &JournalCommitBlock) -> bool {
+        if !self.has_csum_v2v3() {
+            return true;
+        }
+        let offset = JBD2_HEADER_SIZE + 4;
+        let csum = crc32c(self.csum_seed, &data[..offset]);
+        let csum = crc32c(crc32c(csum, &[0; 4]), &data[offset + 4..]);
+        commit.h_chksum[0] == csum
+    }
+
+    /// v3 tags keep the whole crc32c of sequence and block, v2 tags its low 16 bits
+    fn tag_csum_ok(&self, journal_block: u64, sequence: u32, checksum: u32) -> bool {
+        if !self.has_csum_v2v3() {
+            return true;
+        }
+        let Some(data) = self.block(journal_block) else {
+            return false;
+        };
+        let csum = crc32c(crc32c(self.csum_seed, &sequence.to_be_bytes()), &data);
+        if self.has_incompat(JournalIncompatFeatures::CSUM_V3) { checksum == csum } else { checksum == csum & 0xffff }
+    }
+
+    /// Block number, flags and checksum of each tag of a descriptor block
+    fn parse_tags(&self, data: &[u8]) -> Vec<(u64, JournalTagFlags, u32)> {
+        let tag_bytes = self.tag_bytes();
+        let end = data.len() - if self.has_csum_v2v3() { JBD2_BLOCK_TAIL_SIZE } else { 0 };
+        let has_64bit = self.has_incompat(JournalIncompatFeatures::_64BIT);
+        let csum_v3 = self.has_incompat(JournalIncompatFeatures::CSUM_V3);
+        let mut tags = Vec::new();
+        let mut offset = JBD2_HEADER_SIZE;
+        while offset + tag_bytes <= end {
+            let tag = &data[offset..offset + tag_bytes];
+            let word = |at: usize| u32::from_be_bytes(tag[at..at + 4].try_into().unwrap());
+            let (block_hi, flags, checksum) = if csum_v3 {
+                (word(8), word(4), word(12))
+            } else {
+                let flags = u16::from_be_bytes(tag[6..8].try_into().unwrap()) as u32;
+                let checksum = u16::from_be_bytes(tag[4..6].try_into().unwrap()) as u32;
+                (if has_64bit { word(8) } else { 0 }, flags, checksum)
+            };
+            let flags = JournalTagFlags::from_bits_truncate(flags);
+            let block = if has_64bit { ((block_hi as u64) << 32) | word(0) as u64 } else { word(0) as u64 };
+            tags.push((block, flags, checksum));
+            offset += tag_bytes;
+            if !flags.contains(JournalTagFlags::SAME_UUID) {
+                offset += JBD2_UUID_SIZE;
+            }
+            if flags.contains(JournalTagFlags::LAST_TAG) {
+                break;
+            }
+        }
+        tags
+    }
+
+    /// Blocks listed in a revoke block, `r_count` bytes including the header
+    fn parse_revoke(&self, data: &[u8]) -> Vec<u64> {
+        let count = u32::from_be_bytes(data[JBD2_HEADER_SIZE..JBD2_REVOKE_HEADER_SIZE].try_into().unwrap()) as usize;
+        let end = count.min(data.len() - if self.has_csum_v2v3() { JBD2_BLOCK_TAIL_SIZE } else { 0 });
+        let record_size = if self.has_incompat(JournalIncompatFeatures::_64BIT) { 8 } else { 4 };
+        data.get(JBD2_REVOKE_HEADER_SIZE..end)
+            .unwrap_or_default()
+            .chunks_exact(record_size)
+            .map(|record| match record_size {
+                8 => u64::from_be_bytes(record.try_into().unwrap()),
+                _ => u32::from_be_bytes(record.try_into().unwrap()) as u64,
+            })
+            .collect()
+    }
+}
+
+fn map_block<'a>(device: &'a dyn BlockDevice, block_map: &BlockMap, block_size: u64, block: u64) -> Option<Cow<'a, [u8]>> {
+    let run = block_map.find(block)?;
+    device.read_block(run.physical + block - run.logical, block_size)
+}
+
+/// Walks the log one transaction at a time, stopping at the first block that is
+/// not part of the expected transaction or fails its checksum.
+pub struct Transactions<'j, 'a> {
+    journal: &'j Journal<'a>,
+    block: u64,
+    sequence: u32,
+    /// blocks visited so far, bounds the walk on a corrupted log
+    walked: u64,
+}
+
+impl Transactions<'_, '_> {
+    fn advance(&mut self) -> Option<u64> {
+        let journal = self.journal;
+        self.walked += 1;
+        if self.walked > journal.last() - journal.first() {
+            return None;
+        }
+        let block = self.block;
+        self.block = journal.wrap(block + 1);
+        Some(block)
+    }
+}
+
+impl Iterator for Transactions<'_, '_> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let journal = self.journal;
+        let mut transaction = Transaction {
+            sequence: self.sequence,
+            commit_time: Timestamp::default(),
+            start: self.block,
+            commit_block: 0,
+            blocks: Vec::new(),
+            revoked: Vec::new(),
+        };
+        loop {
+            let block = self.block;
+            let data = journal.block(block)?;
+            let data = &*data;
+            let (_, header) = JournalHeader::parse(data).ok()?;
+            if header.h_magic != JBD2_MAGIC_NUMBER || header.h_sequence != self.sequence {
+                return None;
+            }
+            self.advance()?;
+            match header.h_blocktype {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    if !journal.block_tail_csum_ok(data) {
+                        return None;
+                    }
+                    for (fs_block, flags, checksum) in journal.parse_tags(data) {
+                        let journal_block = self.advance()?;
+                        transaction.blocks.push(LoggedBlock {
+                            fs_block,
+                            journal_block,
+                            escaped: flags.contains(JournalTagFlags::ESCAPE),
+                            csum_ok: journal.tag_csum_ok(journal_block, self.sequence, checksum),
+                        });
+                    }
+                }
+                JBD2_REVOKE_BLOCK => {
+                    if !journal.block_tail_csum_ok(data) {
+                        return None;
+                    }
+                    transaction.revoked.extend(journal.parse_revoke(data));
+                }
+                JBD2_COMMIT_BLOCK => {
+                    let (_, commit) = JournalCommitBlock::parse(data).ok()?;
+                    if !journal.commit_csum_ok(data, &commit) {
+                        return None;
+                    }
+                    transaction.commit_time =
+                        Timestamp { seconds: commit.h_commit_sec as i64, nanoseconds: commit.h_commit_nsec };
+                    transaction.commit_block = block;
+                    self.sequence = self.sequence.wrapping_add(1);
+                    return Some(transaction);
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Every journaled copy of the inode table block holding `i_no`, oldest first.
+    /// Unallocated inodes have a history too, which helps tracing deleted files.
+    pub fn inode_history(&self, journal: &Journal, i_no: u64) -> Vec<InodeVersion> {
+        let Some((block, offset)) = self.inode_location(i_no) else {
+            return Vec::new();
+        };
+        let inode_size = self.super_block().s_inode_size as usize;
+        let mut versions = Vec::new();
+        for transaction in journal.transactions() {
+            for logged in transaction.blocks.iter().filter(|logged| logged.fs_block == block) {
+                let data = journal.logged_data(logged);
+                let raw = data.as_deref().and_then(|data| data.get(offset..offset + inode_size));
+                let Some(mut inode) = raw.and_then(Ext4Inode::parse_sized) else {
+                    continue;
+                };
+                inode.i_no = i_no;
+                versions.push(InodeVersion {
+                    sequence: transaction.sequence,
+                    commit_time: transaction.commit_time,
+                    journal_block: logged.journal_block,
+                    csum_ok: logged.csum_ok,
+                    inode,
+                });
+            }
+        }
+        versions
+    }
+
+    /// Opens the internal journal. Its inode's block map falls back to the copy in
+    /// `s_jnl_blocks` when the inode itself maps nothing.
+    pub fn journal(&self) -> Result<Journal<'_>, Err> {
+        let super_block = self.super_block();
+        if !super_block.s_feature_compat.contains(CompatFeatures::HAS_JOURNAL) {
+            return Err("file system has no journal".into());
+        }
+        if super_block.s_journal_inum == 0 {
+            return Err("external journal devices are not supported".into());
+        }
+        let mut inode: Ext4Inode =
+            self.get_inode(super_block.s_journal_inum as u64).ok_or("cannot read the journal inode")?;
+        let block_size = self.block_size();
+        let mut block_map = inode.block_map(self);
+        if block_map.runs.is_empty() && super_block.s_jnl_backup_type == EXT3_JNL_BACKUP_BLOCKS {
+            inode.i_block.copy_from_slice(&super_block.s_jnl_blocks[..EXT4_N_BLOCKS]);
+            inode.i_flags.set(InodeFlags::EXTENTS, inode.get_extend_header().is_header());
+            block_map = inode.block_map(self);
+        }
+        Journal::new(self.device(), block_map, block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_map::BlockRun;
+
+    const BLOCK_SIZE: usize = 1024;
+
+    /// Non-zero spans of the super block, descriptor, revoke and commit blocks of
+    /// a journal holding one transaction written by debugfs `jw -b 300,301 -r 310`,
+    /// which logs the same data to journal blocks 2 and 3.
+    type Fixture = [&'static [(usize, &'static str)]; 4];
+
+    const V3_64BIT: Fixture = [
+        &[
+            (0, "c03b399800000004000000000000040000000400000000010000000100000001000000000000000000000013000000000b7a6e0e6f3d4b5a9e4c2f1d8c7b6a5900000001"),
+            (80, "04"),
+            (252, "2b7f65cd"),
+        ],
+        &[
+            (0, "c03b399800000001000000010000012c0000000000000000d5b5392b"),
+            (46, "012d0000000a00000000d5b5392b"),
+            (204, "0b7a6e0e6f3d4b5a9e4c2f1d8c7b6a59"),
+            (1020, "ea79e22f"),
+        ],
+        &[(0, "c03b39980000000500000001000000180000000000000136"), (1020, "f9b13e9a")],
+        &[(0, "c03b39980000000200000001000000004312bd65"), (48, "6ad3028a0000000000151288")],
+    ];
+
+    const V2_32BIT: Fixture = [
+        &[
+            (0, "c03b399800000004000000000000040000000400000000010000000100000001000000000000000000000009000000000b7a6e0e6f3d4b5a9e4c2f1d8c7b6a5900000001"),
+            (80, "04"),
+            (252, "3c8c2895"),
+        ],
+        &[
+            (0, "c03b399800000001000000010000012c392b"),
+            (40, "012d392b000a"),
+            (132, "0b7a6e0e6f3d4b5a9e4c2f1d8c7b6a59"),
+            (1020, "58af9eb6"),
+        ],
+        &[(0, "c03b399800000005000000010000001400000136"), (1020, "46ca05ed")],
+        &[(0, "c03b39980000000200000001000000003d6525e2"), (48, "6ad3028a0000000001243c80")],
+    ];
+
+    const V2_64BIT: Fixture = [
+        &[
+            (0, "c03b39980000000400000000000004000000040000000001000000010000000100000000000000000000000b000000000b7a6e0e6f3d4b5a9e4c2f1d8c7b6a5900000001"),
+            (80, "04"),
+            (252, "0529b0c0"),
+        ],
+        &[
+            (0, "c03b399800000001000000010000012c392b"),
+            (44, "012d392b000a"),
+            (180, "0b7a6e0e6f3d4b5a9e4c2f1d8c7b6a59"),
+            (1020, "dc2ecac9"),
+        ],
+        &[(0, "c03b39980000000500000001000000180000000000000136"), (1020, "f9b13e9a")],
+        &[(0, "c03b399800000002000000010000000000c5ac3e"), (48, "6ad3028a0000000002612180")],
+    ];
+
+    const NO_CSUM_32BIT: Fixture = [
+        &[(0, "c03b399800000004000000000000040000000400000000010000000100000001000000000000000000000001000000000b7a6e0e6f3d4b5a9e4c2f1d8c7b6a5900000001")],
+        &[
+            (0, "c03b399800000001000000010000012c"),
+            (38, "012d0000000a"),
+            (108, "0b7a6e0e6f3d4b5a9e4c2f1d8c7b6a59"),
+        ],
+        &[(0, "c03b399800000005000000010000001400000136")],
+        &[(0, "c03b39980000000200000001"), (48, "6ad3028a00000000039e29a8")],
+    ];
+
+    fn image(fixture: &Fixture) -> Vec<u8> {
+        let mut image = vec![0; 6 * BLOCK_SIZE];
+        for (&block, spans) in [0, 1, 4, 5].iter().zip(fixture) {
+            for &(offset, hex) in *spans {
+                let start = block * BLOCK_SIZE + offset;
+                for (i, byte) in (0..hex.len()).step_by(2).enumerate() {
+                    image[start + i] = u8::from_str_radix(&hex[byte..byte + 2], 16).unwrap();
+                }
+            }
+        }
+        for (i, byte) in image[2 * BLOCK_SIZE..4 * BLOCK_SIZE].iter_mut().enumerate() {
+            *byte = (i * 7 + 3) as u8;
+        }
+        image
+    }
+
+    fn check(fixture: &Fixture, incompat: JournalIncompatFeatures, checksums: [u32; 2]) {
+        let image = image(fixture);
+        let block_map = BlockMap::new(vec![BlockRun { logical: 0, physical: 0, len: 6, uninit: false }]);
+        let journal = Journal::new(&image, block_map, BLOCK_SIZE as u64).unwrap();
+        assert_eq!(journal.super_block.s_feature_incompat, incompat);
+
+        let last = JournalTagFlags::SAME_UUID | JournalTagFlags::LAST_TAG;
+        let tags = journal.parse_tags(&journal.block(1).unwrap());
+        assert_eq!(tags, [(300, JournalTagFlags::empty(), checksums[0]), (301, last, checksums[1])]);
+
+        let transactions = journal.transactions().collect::<Vec<_>>();
+        assert_eq!(transactions.len(), 1);
+        let transaction = &transactions[0];
+        assert_eq!((transaction.sequence, transaction.start, transaction.commit_block), (1, 1, 5));
+        assert_eq!(transaction.revoked, [310]);
+        let logged = |fs_block, journal_block| LoggedBlock { fs_block, journal_block, escaped: false, csum_ok: true };
+        assert_eq!(transaction.blocks, [logged(300, 2), logged(301, 3)]);
+    }
+
+    #[test]
+    fn csum_v3_64bit_tags() {
+        let incompat = JournalIncompatFeatures::REVOKE | JournalIncompatFeatures::_64BIT | JournalIncompatFeatures::CSUM_V3;
+        check(&V3_64BIT, incompat, [0xd5b5_392b; 2]);
+    }
+
+    #[test]
+    fn csum_v2_32bit_tags() {
+        check(&V2_32BIT, JournalIncompatFeatures::REVOKE | JournalIncompatFeatures::CSUM_V2, [0x392b; 2]);
+    }
+
+    #[test]
+    fn csum_v2_64bit_tags() {
+        let incompat = JournalIncompatFeatures::REVOKE | JournalIncompatFeatures::_64BIT | JournalIncompatFeatures::CSUM_V2;
+        check(&V2_64BIT, incompat, [0x392b; 2]);
+    }
+
+    #[test]
+    fn plain_32bit_tags() {
+        check(&NO_CSUM_32BIT, JournalIncompatFeatures::REVOKE, [0; 2]);
+    }
+
+    #[test]
+    fn bad_tag_checksum() {
+        let mut image = image(&V3_64BIT);
+        image[3 * BLOCK_SIZE] ^= 1;
+        let block_map = BlockMap::new(vec![BlockRun { logical: 0, physical: 0, len: 6, uninit: false }]);
+        let journal = Journal::new(&image, block_map, BLOCK_SIZE as u64).unwrap();
+        let csum_ok = journal.transactions().flat_map(|transaction| transaction.blocks).map(|logged| logged.csum_ok);
+        assert_eq!(csum_ok.collect::<Vec<_>>(), [true, false]);
+    }
+
+    #[test]
+    fn bad_log_geometry() {
+        let new = |patches: &[(usize, u32)]| {
+            let mut image = image(&NO_CSUM_32BIT);
+            for &(offset, value) in patches {
+                image[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+            }
+            let block_map = BlockMap::new(vec![BlockRun { logical: 0, physical: 0, len: 6, uninit: false }]);
+            Journal::new(&image, block_map, BLOCK_SIZE as u64).map(|journal| (journal.first(), journal.last()))
+        };
+        // s_maxlen at 16, s_first at 20, s_feature_incompat at 40, s_num_fc_blks at 84
+        let fast_commit = (JournalIncompatFeatures::REVOKE | JournalIncompatFeatures::FAST_COMMIT).bits();
+        assert_eq!(new(&[(40, fast_commit), (84, 24)]), Ok((1, 1000)));
+        assert!(new(&[(20, 0)]).is_err());
+        assert!(new(&[(20, 1024)]).is_err());
+        assert!(new(&[(40, fast_commit), (84, 1024)]).is_err());
+        assert!(new(&[(16, 0)]).is_err());
+    }
+}
@@ -1,3 +1,19 @@
 pub mod defs;
+pub mod device;
 pub mod fs_parser;
 pub mod chain;
+pub mod block_map;
+pub mod xattr;
+pub mod htree;
+pub mod metadata;
+pub mod csum;
+pub mod journal;
+pub mod fast_commit;
+pub mod orphan;
+pub mod undelete;
+pub mod carve;
+pub mod cache;
+pub mod walk;
+pub mod file;
+#[cfg(feature = "async")]
+pub mod async_fs;
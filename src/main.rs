@@ -1,22 +1,18 @@
-use rext4::{defs::BlockContents, fs_parser::Ext4Fs};
+use rext4::{cache::Cache, defs::BlockContents, device::{BlockDevice, FileDevice}, fs_parser::Ext4Fs};
 use std::collections::VecDeque;
 use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::sync::Arc;
 
 fn main() {
     let file_path = env::args().nth(1).expect("file path required");
-    let mut ext4_file = File::open(file_path).expect("file path does not exists");
-    let mut contents = Vec::new();
-    ext4_file
-        .read_to_end(&mut contents)
-        .expect("failed to read to end");
+    let device = FileDevice::open(file_path).expect("file path does not exists");
 
-    let ext4_fs = Ext4Fs::from_file(&contents).unwrap();
+    let mut ext4_fs = Ext4Fs::new(device).unwrap();
+    ext4_fs.set_cache(Arc::new(Cache::default()));
     traverse_file(&ext4_fs)
 }
 
-fn traverse_file(ext4_fs: &Ext4Fs) {
+fn traverse_file<D: BlockDevice>(ext4_fs: &Ext4Fs<D>) {
     let root_inode_num = 2;
     let mut queue: VecDeque<(u64, u64, String)> = VecDeque::new();
     queue.push_back((root_inode_num, root_inode_num, "".into()));
@@ -48,9 +44,14 @@ fn traverse_file(ext4_fs: &Ext4Fs) {
                             }
                         }
                     },
+                    BlockContents::Special(rdev) => {
+                        if let Some(rdev) = rdev {
+                            println!("device {}:{}", rdev.major, rdev.minor)
+                        }
+                    }
                     BlockContents::InliedData(data) => {
                         if inode.i_mode.ty.is_symlink() {
-                            let name = String::from_utf8(data.into()).unwrap_or("failed to get data!".into());
+                            let name = String::from_utf8(data.into_owned()).unwrap_or("failed to get data!".into());
                             println!("symlink to {name}")
                         }
                     }
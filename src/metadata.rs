@@ -0,0 +1,111 @@
+use crate::defs::{DeviceNumber, Ext4Inode, FilePermissions, FileType, InodeFlags, RoCompatFeatures};
+use crate::device::BlockDevice;
+use crate::fs_parser::Ext4Fs;
+
+/// Low bits of a `*_extra` time field extending the seconds past 2038
+const EXT4_EPOCH_BITS: u32 = 2;
+const EXT4_EPOCH_MASK: u32 = (1 << EXT4_EPOCH_BITS) - 1;
+
+// byte offsets of the extra fields in the on-disk inode
+const I_CTIME_EXTRA_OFFSET: usize = 0x84;
+const I_MTIME_EXTRA_OFFSET: usize = 0x88;
+const I_ATIME_EXTRA_OFFSET: usize = 0x8C;
+const I_CRTIME_OFFSET: usize = 0x90;
+const I_CRTIME_EXTRA_OFFSET: usize = 0x94;
+const I_VERSION_HI_OFFSET: usize = 0x98;
+const I_PROJID_OFFSET: usize = 0x9C;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Timestamp {
+    /// seconds since the Unix epoch
+    pub seconds: i64,
+    pub nanoseconds: u32,
+}
+
+impl Timestamp {
+    /// Combines a signed 32-bit time with its `*_extra` field, if the inode has one.
+    pub fn decode(time: u32, extra: Option<u32>) -> Self {
+        let mut seconds = time as i32 as i64;
+        let mut nanoseconds = 0;
+        if let Some(extra) = extra {
+            seconds += ((extra & EXT4_EPOCH_MASK) as i64) << 32;
+            nanoseconds = extra >> EXT4_EPOCH_BITS;
+        }
+        Self { seconds, nanoseconds }
+    }
+}
+
+/// Inode fields with their split halves and extra fields put back together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeMetadata {
+    pub i_no: u64,
+    pub file_type: FileType,
+    pub permissions: FilePermissions,
+    pub uid: u32,
+    pub gid: u32,
+    /// size in bytes
+    pub size: u64,
+    pub links_count: u16,
+    /// allocated space in 512-byte sectors
+    pub blocks: u64,
+    /// block holding the external extended attributes
+    pub file_acl: u64,
+    pub flags: InodeFlags,
+    pub generation: u32,
+    pub atime: Timestamp,
+    pub ctime: Timestamp,
+    pub mtime: Timestamp,
+    /// creation time, only inodes with room for the extra fields record it
+    pub crtime: Option<Timestamp>,
+    /// deletion time, plain seconds
+    pub dtime: u32,
+    pub version: u64,
+    pub projid: Option<u32>,
+    pub rdev: Option<DeviceNumber>,
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    pub fn metadata(&self, inode: &Ext4Inode) -> InodeMetadata {
+        let extra = |offset: usize, value: u32| inode.fits_in_inode(offset, 4).then_some(value);
+
+        let crtime = extra(I_CRTIME_OFFSET, inode.i_crtime)
+            .map(|crtime| Timestamp::decode(crtime, extra(I_CRTIME_EXTRA_OFFSET, inode.i_crtime_extra)));
+        let version_hi = extra(I_VERSION_HI_OFFSET, inode.i_version_hi).unwrap_or(0);
+
+        InodeMetadata {
+            i_no: inode.i_no,
+            file_type: inode.i_mode.ty,
+            permissions: inode.i_mode.perms,
+            uid: ((inode.osd2.l_i_uid_high as u32) << 16) | inode.i_uid as u32,
+            gid: ((inode.osd2.l_i_gid_high as u32) << 16) | inode.i_gid as u32,
+            size: inode.size(),
+            links_count: inode.i_links_count,
+            blocks: self.i_blocks(inode),
+            file_acl: inode.file_acl(),
+            flags: inode.i_flags,
+            generation: inode.i_generation,
+            atime: Timestamp::decode(inode.i_atime, extra(I_ATIME_EXTRA_OFFSET, inode.i_atime_extra)),
+            ctime: Timestamp::decode(inode.i_ctime, extra(I_CTIME_EXTRA_OFFSET, inode.i_ctime_extra)),
+            mtime: Timestamp::decode(inode.i_mtime, extra(I_MTIME_EXTRA_OFFSET, inode.i_mtime_extra)),
+            crtime,
+            dtime: inode.i_dtime,
+            version: ((version_hi as u64) << 32) | inode.osd1.l_i_version as u64,
+            projid: extra(I_PROJID_OFFSET, inode.i_projid),
+            rdev: inode.rdev(),
+        }
+    }
+
+    /// Allocated space in 512-byte sectors. With huge_file the count has 48 bits
+    /// and inodes flagged `HUGE_FILE` count filesystem blocks instead.
+    pub fn i_blocks(&self, inode: &Ext4Inode) -> u64 {
+        if !self.super_block().s_feature_ro_compat.contains(RoCompatFeatures::HUGE_FILE) {
+            return inode.i_blocks_lo as u64;
+        }
+        let i_blocks = ((inode.osd2.l_i_blocks_high as u64) << 32) | inode.i_blocks_lo as u64;
+        if inode.i_flags.contains(InodeFlags::HUGE_FILE) {
+            i_blocks * (self.block_size() >> 9)
+        } else {
+            i_blocks
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::block_map::map_node_blocks;
+use crate::csum::CsumCheck;
+use crate::defs::{CompatFeatures, Ext4Inode, Ext4XattrHeader, InodeFlags, RoCompatFeatures};
+use crate::fast_commit::RangeEdit;
+use crate::device::BlockDevice;
+use crate::fs_parser::{Err, Ext4Fs};
+use crate::journal::BlockOverlay;
+use crate::xattr::EXT4_XATTR_MAGIC;
+use nom_derive::Parse;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// `ob_magic` of the tail closing every orphan file block
+const EXT4_ORPHAN_BLOCK_MAGIC: u32 = 0x0b10_ca04;
+/// `ob_magic` followed by `ob_checksum`
+pub(crate) const EXT4_ORPHAN_BLOCK_TAIL_SIZE: usize = 8;
+
+/// Where an orphan was recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanSource {
+    /// chained from `s_last_orphan` through `i_dtime`
+    List,
+    /// entry of the orphan file, `block` counted from the start of the file
+    File { block: u64 },
+}
+
+/// An inode that was being deleted or truncated when the image was taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub i_no: u64,
+    pub source: OrphanSource,
+    /// `None` when the inode cannot be read
+    pub inode: Option<Ext4Inode>,
+}
+
+impl Orphan {
+    /// Orphans with links left were being truncated to `i_size`, the others deleted
+    pub fn is_truncate(&self) -> bool {
+        self.inode.is_some_and(|inode| inode.i_links_count != 0)
+    }
+}
+
+/// A block of the orphan file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanFileBlock {
+    /// block of the orphan file and where it is stored
+    pub logical: u64,
+    pub physical: u64,
+    /// the tail carries the orphan block magic
+    pub magic_ok: bool,
+    /// checksum of the block, `None` without metadata_csum
+    pub csum: Option<CsumCheck>,
+    /// non-zero entries of the block
+    pub inodes: Vec<u64>,
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Inodes chained from `s_last_orphan`, each holding the next one in `i_dtime`.
+    /// The walk stops after an inode that cannot be read or on a loop.
+    pub fn orphan_list(&self) -> Vec<u64> {
+        let mut chain = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut i_no = self.super_block().s_last_orphan as u64;
+        while i_no != 0 && seen.insert(i_no) {
+            chain.push(i_no);
+            let Some(inode) = self.get_inode(i_no) else {
+                break;
+            };
+            i_no = inode.i_dtime as u64;
+        }
+        chain
+    }
+
+    /// Blocks of the orphan file, an array of inode numbers in each block followed
+    /// by a magic and a checksum.
+    pub fn orphan_file(&self) -> Result<Vec<OrphanFileBlock>, Err> {
+        let super_block = self.super_block();
+        if !super_block.s_feature_compat.contains(CompatFeatures::ORPHAN_PRESENT) {
+            return Err("file system has no orphan file".into());
+        }
+        let inode = self.get_inode(super_block.s_orphan_file_inum as u64).ok_or("cannot read the orphan file inode")?;
+        let block_size = self.block_size();
+        let entries_size = block_size as usize - EXT4_ORPHAN_BLOCK_TAIL_SIZE;
+        let block_map = inode.block_map(self);
+        let mut blocks = Vec::new();
+        for logical in 0..inode.size() / block_size {
+            let Some(run) = block_map.find(logical) else {
+                continue;
+            };
+            let physical = run.physical + logical - run.logical;
+            let Some(data) = self.get_block(physical) else {
+                continue;
+            };
+            let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            blocks.push(OrphanFileBlock {
+                logical,
+                physical,
+                magic_ok: word(entries_size) == EXT4_ORPHAN_BLOCK_MAGIC,
+                csum: self.orphan_block_csum(&inode, physical, &data),
+                inodes: (0..entries_size).step_by(4).map(word).filter(|&i_no| i_no != 0).map(u64::from).collect(),
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Orphans of the legacy list followed by those of the orphan file. Orphan file
+    /// entries are listed even when `ORPHAN_PRESENT` is clear, recovery ignores them then.
+    pub fn orphans(&self) -> Vec<Orphan> {
+        let orphan = |i_no, source| Orphan { i_no, source, inode: self.get_inode(i_no) };
+        let mut orphans: Vec<Orphan> = self.orphan_list().into_iter().map(|i_no| orphan(i_no, OrphanSource::List)).collect();
+        for block in self.orphan_file().unwrap_or_default() {
+            let source = OrphanSource::File { block: block.logical };
+            orphans.extend(block.inodes.iter().map(|&i_no| orphan(i_no, source)));
+        }
+        orphans
+    }
+
+    /// Orphan cleanup of `BlockOverlay::process_orphans`, on the overlay lent to
+    /// the file system
+    fn replay_orphans(&mut self) {
+        let has_orphan_file = self.super_block().s_feature_ro_compat.contains(RoCompatFeatures::ORPHAN_PRESENT);
+        let mut patched = BTreeMap::new();
+        let mut edits = Vec::new();
+        for orphan in self.orphans() {
+            if matches!(orphan.source, OrphanSource::File { .. }) && !has_orphan_file {
+                continue;
+            }
+            let Some(inode) = orphan.inode else {
+                continue;
+            };
+            let first = if orphan.is_truncate() { inode.size().div_ceil(self.block_size()) } else { 0 };
+            // device numbers, inline data and fast symlink targets are not a block map
+            let has_map = !inode.i_mode.ty.is_special()
+                && !inode.i_flags.contains(InodeFlags::INLINE_DATA)
+                && !inode.is_fast_symlink(self.super_block().s_log_cluster_size);
+            if has_map {
+                for run in inode.block_map(self).range(first, u64::MAX) {
+                    self.mark_blocks(&mut patched, run.physical, run.len, false);
+                }
+            }
+            if !orphan.is_truncate() {
+                if has_map {
+                    for block in map_node_blocks(&inode, self) {
+                        self.mark_blocks(&mut patched, block, 1, false);
+                    }
+                }
+                if self.owns_xattr_block(&inode) {
+                    self.mark_blocks(&mut patched, inode.file_acl(), 1, false);
+                }
+                self.mark_inode(&mut patched, orphan.i_no, false);
+            }
+            edits.push((orphan.i_no, RangeEdit::Unmap { logical: first, len: u64::MAX }));
+        }
+        let overlay = self.overlay_mut();
+        for (block, data) in patched {
+            overlay.insert(block, data);
+        }
+        for (i_no, edit) in edits {
+            overlay.range_edits.entry(i_no).or_default().push(edit);
+        }
+    }
+
+    /// Whether the inode is the only user of its external xattr block, which then
+    /// goes with it
+    fn owns_xattr_block(&self, inode: &Ext4Inode) -> bool {
+        let block = inode.file_acl();
+        if block == 0 {
+            return false;
+        }
+        self.get_block(block)
+            .and_then(|data| Ext4XattrHeader::parse(&data).ok().map(|(_, header)| header))
+            .is_some_and(|header| header.h_magic == EXT4_XATTR_MAGIC && header.h_refcount == 1)
+    }
+}
+
+impl BlockOverlay {
+    /// Runs the orphan cleanup of mount time on the overlay: orphans without links
+    /// are deleted, the others truncated to `i_size`. A deleted orphan releases its
+    /// inode, data blocks, extent tree or indirect blocks and an xattr block no
+    /// other inode shares; a truncated one only the data blocks past its size.
+    /// Released inodes and blocks are cleared in the bitmaps and the block maps
+    /// cut through range edits. The orphan records themselves, the reference
+    /// count of a shared xattr block, free counts and bitmap checksums are left
+    /// as they were.
+    pub fn process_orphans<D: BlockDevice + ?Sized>(&mut self, device: &D) -> Result<(), Err> {
+        Ext4Fs::lend_overlay(device, self, Ext4Fs::replay_orphans)
+    }
+}
@@ -0,0 +1,299 @@
+use crate::block_map::BlockMap;
+use crate::chain::ChainItem;
+use crate::csum::rec_len;
+use crate::defs::{
+    BgFlags, BlockContents, Ext4DirEntry, Ext4Inode, InodeFlags, EXT4_I_BLOCK_OFFSET, EXT4_MIN_INLINE_DATA_SIZE,
+};
+use crate::device::BlockDevice;
+use crate::fs_parser::Ext4Fs;
+use crate::journal::{Journal, LoggedBlock};
+use crate::metadata::InodeMetadata;
+use crate::xattr;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How far recovered content can be trusted, lowest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// some mapped blocks are in use again, or nothing of the map survived
+    Low,
+    /// the map comes from a journal copy of the inode and its blocks are still free
+    Medium,
+    /// the inode kept its own map and its blocks are still free
+    High,
+}
+
+/// Where the block map of a deleted inode was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSource {
+    /// the inode table
+    Inode,
+    /// copy of the inode table block logged by transaction `sequence`
+    Journal { sequence: u32 },
+}
+
+/// A deleted inode and what is left of it
+#[derive(Debug, Clone)]
+pub struct DeletedInode {
+    pub i_no: u64,
+    /// the bitmap still marks the inode in use, only `i_dtime` tells it was deleted
+    pub allocated: bool,
+    /// timestamps, size and owner as the inode table holds them
+    pub metadata: InodeMetadata,
+    /// version of the inode the map is taken from, a journal copy keeps the size
+    /// the inode had before the delete truncated it
+    pub inode: Ext4Inode,
+    pub source: MapSource,
+    pub block_map: BlockMap,
+    /// mapped blocks the bitmap marks in use again, or that cannot be checked
+    pub reused_blocks: u64,
+    pub confidence: Confidence,
+    /// raw bytes of `inode`, inline data is read from them
+    raw: Vec<u8>,
+}
+
+/// Content read back from a deleted inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredFile {
+    pub i_no: u64,
+    pub source: MapSource,
+    pub confidence: Confidence,
+    pub data: Vec<u8>,
+}
+
+/// A directory entry recovered from the space a live entry's `rec_len` covers
+/// past its name, where unlinked entries are merged
+#[derive(Debug, Clone, Copy)]
+pub struct DeletedDirEntry {
+    /// buffer of the directory's entry chain, the logical block unless the
+    /// directory is inline
+    pub block: usize,
+    /// byte offset of the entry in the buffer
+    pub offset: usize,
+    /// the entry as found, `inode` being the former inode
+    pub entry: Ext4DirEntry,
+    /// the bitmap marks the former inode in use again, which it also does when the
+    /// entry was renamed or the file has other links. `None` when it cannot be told
+    pub reallocated: Option<bool>,
+}
+
+/// Highest `EXT4_FT_*` value
+const EXT4_FT_MAX: u8 = 7;
+
+/// Bytes an entry with a `name_len` byte name needs
+fn dir_entry_len(name_len: u8) -> usize {
+    (Ext4DirEntry::MIN_LEN + name_len as usize).next_multiple_of(4)
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Scans the inode tables for inodes the bitmap marks free but that were used
+    /// once, and for allocated inodes with a deletion time that are not on the
+    /// orphan list. When the delete zeroed an inode's map, the newest copy of it
+    /// in `journal` with the same generation and a map is used instead.
+    pub fn deleted_inodes(&self, journal: Option<&Journal>) -> Vec<DeletedInode> {
+        let super_block = self.super_block();
+        let inodes_per_group = super_block.s_inodes_per_group as u64;
+        let orphans: BTreeSet<u64> = self.orphan_list().into_iter().collect();
+        let mut deleted = Vec::new();
+        for (group, group_desc) in self.group_descs.iter().enumerate() {
+            if group_desc.bg_flags.contains(BgFlags::INODE_UNINIT) {
+                continue;
+            }
+            for i_no in group as u64 * inodes_per_group + 1..=(group as u64 + 1) * inodes_per_group {
+                if i_no < super_block.s_first_ino as u64 {
+                    continue;
+                }
+                let (Some(allocated), Some(raw)) = (self.is_inode_used(i_no), self.get_inode_bytes_unchecked(i_no)) else {
+                    continue;
+                };
+                let Some(mut inode) = Ext4Inode::parse_sized(&raw) else {
+                    continue;
+                };
+                inode.i_no = i_no;
+                let was_used = u16::from_le_bytes([raw[0], raw[1]]) != 0 || inode.i_dtime != 0;
+                let is_deleted = if allocated { inode.i_dtime != 0 && !orphans.contains(&i_no) } else { was_used };
+                if is_deleted {
+                    deleted.push(self.deleted_inode(inode, raw.into_owned(), allocated));
+                }
+            }
+        }
+        if let Some(journal) = journal {
+            self.recover_maps_from_journal(journal, &mut deleted);
+        }
+        deleted
+    }
+
+    /// Content of a deleted inode read through its recovered map, up to the size of
+    /// the version the map comes from but not past its last mapped block, nor past
+    /// the first block that cannot be read
+    pub fn undelete(&self, deleted: &DeletedInode) -> RecoveredFile {
+        let inode = &deleted.inode;
+        let data = if inode.i_flags.contains(InodeFlags::INLINE_DATA) {
+            let i_block = EXT4_I_BLOCK_OFFSET..EXT4_I_BLOCK_OFFSET + EXT4_MIN_INLINE_DATA_SIZE;
+            let mut data = deleted.raw.get(i_block).unwrap_or_default().to_vec();
+            data.extend_from_slice(xattr::inline_data_value(&deleted.raw, inode.i_extra_isize).unwrap_or_default());
+            data.truncate(inode.size() as usize);
+            data
+        } else {
+            let mapped = deleted.block_map.runs.iter().map(|run| run.logical + run.len).max().unwrap_or(0);
+            let mut data = vec![0; inode.size().min(mapped.saturating_mul(self.block_size())) as usize];
+            let block_size = self.block_size() as usize;
+            let mut len = 0;
+            while len < data.len() {
+                let end = (len + block_size).min(data.len());
+                match self.read_at(inode, len as u64, &mut data[len..end]) {
+                    Some(read) if read > 0 => len += read,
+                    _ => break,
+                }
+            }
+            data.truncate(len);
+            data
+        };
+        RecoveredFile { i_no: deleted.i_no, source: deleted.source, confidence: deleted.confidence, data }
+    }
+
+    fn deleted_inode(&self, inode: Ext4Inode, raw: Vec<u8>, allocated: bool) -> DeletedInode {
+        let mut deleted = DeletedInode {
+            i_no: inode.i_no,
+            allocated,
+            metadata: self.metadata(&inode),
+            inode,
+            source: MapSource::Inode,
+            block_map: BlockMap::default(),
+            reused_blocks: 0,
+            confidence: Confidence::Low,
+            raw,
+        };
+        self.set_map(&mut deleted, inode, MapSource::Inode);
+        deleted
+    }
+
+    /// Takes the map of `inode` for `deleted` and grades it
+    fn set_map(&self, deleted: &mut DeletedInode, inode: Ext4Inode, source: MapSource) {
+        let blocks_count = self.super_block().blocks_count();
+        let block_map = if inode.i_flags.contains(InodeFlags::INLINE_DATA) { BlockMap::default() } else { inode.block_map(self) };
+        let mut reused_blocks = 0;
+        for run in &block_map.runs {
+            if run.physical.saturating_add(run.len) > blocks_count {
+                reused_blocks += run.len;
+                continue;
+            }
+            reused_blocks += (run.physical..run.physical + run.len)
+                .filter(|&block| self.is_block_used(block) != Some(false))
+                .count() as u64;
+        }
+        let has_content = !block_map.runs.is_empty() || inode.i_flags.contains(InodeFlags::INLINE_DATA);
+        deleted.confidence = match source {
+            _ if reused_blocks != 0 || !has_content => Confidence::Low,
+            MapSource::Inode => Confidence::High,
+            MapSource::Journal { .. } => Confidence::Medium,
+        };
+        deleted.inode = inode;
+        deleted.source = source;
+        deleted.block_map = block_map;
+        deleted.reused_blocks = reused_blocks;
+    }
+
+    /// Looks for journal copies of the inodes whose map is empty, reading the log
+    /// once for all of them
+    fn recover_maps_from_journal(&self, journal: &Journal, deleted: &mut [DeletedInode]) {
+        let needs_map = |deleted: &DeletedInode| {
+            deleted.block_map.runs.is_empty() && !deleted.inode.i_flags.contains(InodeFlags::INLINE_DATA)
+        };
+        let wanted: BTreeSet<u64> = deleted
+            .iter()
+            .filter(|deleted| needs_map(deleted))
+            .filter_map(|deleted| Some(self.inode_location(deleted.i_no)?.0))
+            .collect();
+        if wanted.is_empty() {
+            return;
+        }
+        let mut copies: BTreeMap<u64, Vec<(u32, LoggedBlock)>> = BTreeMap::new();
+        for transaction in journal.transactions() {
+            for logged in transaction.blocks.iter().filter(|logged| wanted.contains(&logged.fs_block)) {
+                copies.entry(logged.fs_block).or_default().push((transaction.sequence, *logged));
+            }
+        }
+
+        let inode_size = self.super_block().s_inode_size as usize;
+        for deleted in deleted.iter_mut().filter(|deleted| needs_map(deleted)) {
+            let Some((block, offset)) = self.inode_location(deleted.i_no) else {
+                continue;
+            };
+            // newest copy first
+            for &(sequence, logged) in copies.get(&block).into_iter().flatten().rev() {
+                let Some(data) = journal.logged_data(&logged) else {
+                    continue;
+                };
+                let Some(raw) = data.get(offset..offset + inode_size) else {
+                    continue;
+                };
+                let Some(mut inode) = Ext4Inode::parse_sized(raw) else {
+                    continue;
+                };
+                inode.i_no = deleted.i_no;
+                if inode.i_generation != deleted.metadata.generation || u16::from_le_bytes([raw[0], raw[1]]) == 0 {
+                    continue;
+                }
+                let is_inline = inode.i_flags.contains(InodeFlags::INLINE_DATA);
+                if is_inline || !inode.block_map(self).runs.is_empty() {
+                    deleted.raw = raw.to_vec();
+                    self.set_map(deleted, inode, MapSource::Journal { sequence });
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Walks the entries of `dir` like its `Dentries` chain does, and parses the
+    /// plausible entries hidden in the slack behind each of them. Index nodes of
+    /// hashed directories are skipped.
+    pub fn deleted_dir_entries(&self, dir: &Ext4Inode) -> Vec<DeletedDirEntry> {
+        let Some(BlockContents::Dentries(entries)) = self.get_inode_block_contents(dir) else {
+            return Vec::new();
+        };
+        let block_size = self.block_size() as usize;
+        let indexed = dir.i_flags.contains(InodeFlags::INDEX);
+        let mut deleted = Vec::new();
+        for (block, buffer) in entries.into_buffers().enumerate() {
+            let mut offset = 0;
+            while offset + Ext4DirEntry::MIN_LEN <= buffer.len() {
+                let (_, live) = Ext4DirEntry::from_bytes(&buffer[offset..]);
+                let end = offset + rec_len(live.rec_len, block_size);
+                if end <= offset || end > buffer.len() || (indexed && live.inode == 0 && end - offset == block_size) {
+                    break;
+                }
+                let mut slack = offset + dir_entry_len(live.name_len);
+                while slack + Ext4DirEntry::MIN_LEN <= end {
+                    let Some(entry) = self.plausible_dir_entry(&buffer[slack..end]) else {
+                        slack += 4;
+                        continue;
+                    };
+                    let reallocated = self.is_inode_used(entry.inode as u64);
+                    deleted.push(DeletedDirEntry { block, offset: slack, entry, reallocated });
+                    slack += dir_entry_len(entry.name_len);
+                }
+                // the slack of `..` in the first block of a hashed directory is the dx_root
+                if indexed && block == 0 && live.name_bytes() == b".." {
+                    break;
+                }
+                offset = end;
+            }
+        }
+        deleted
+    }
+
+    /// Entry at the start of `gap` if it looks like one the kernel wrote and fits in `gap`
+    fn plausible_dir_entry(&self, gap: &[u8]) -> Option<Ext4DirEntry> {
+        let (_, entry) = Ext4DirEntry::from_bytes(gap);
+        let name_len = gap[6];
+        let rec_len = entry.rec_len as usize;
+        let is_plausible = (1..=self.super_block().s_inodes_count).contains(&entry.inode)
+            && name_len != 0
+            && entry.name_len == name_len
+            && entry.file_type <= EXT4_FT_MAX
+            && rec_len.is_multiple_of(4)
+            && (dir_entry_len(name_len)..=gap.len()).contains(&rec_len)
+            && !entry.name_bytes().iter().any(|&byte| byte == 0 || byte == b'/');
+        is_plausible.then_some(entry)
+    }
+}
@@ -0,0 +1,287 @@
+use crate::carve::FreeBlocks;
+use crate::defs::{BgFlags, BlockContents, Ext4Inode};
+use crate::device::{BlockDevice, FileDevice};
+use crate::fs_parser::Ext4Fs;
+use crate::journal::Journal;
+use std::collections::{BTreeMap, HashSet};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// Items a channel returned by `walk_channel` or `scan_inodes_channel` holds
+/// before the walk waits for the receiver
+pub const CHANNEL_BOUND: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// worker threads, 0 for one per available core
+    pub threads: usize,
+    /// deliver items in the order a single thread would find them. The callback
+    /// is then called by one thread at a time, and items found ahead of the
+    /// next one in order are held until it is delivered.
+    pub ordered: bool,
+}
+
+impl WalkOptions {
+    fn workers(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        }
+    }
+}
+
+/// An entry found by `Ext4Fs::walk`
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    /// `/`-separated names from the directory the walk started at, which need
+    /// not be UTF-8
+    pub path: Vec<u8>,
+    /// directory holding the entry
+    pub parent: u64,
+    pub i_no: u64,
+    /// `file_type` of the directory entry
+    pub file_type: u8,
+    /// `None` when the inode cannot be read
+    pub inode: Option<Ext4Inode>,
+}
+
+impl WalkEntry {
+    pub fn is_dir(&self) -> bool {
+        self.inode.is_some_and(|inode| inode.i_mode.ty.is_dir())
+    }
+}
+
+// the walkers share the file system and its readers between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Ext4Fs<FileDevice>>();
+    assert_send_sync::<BlockContents<'_>>();
+    assert_send_sync::<FreeBlocks<'_, FileDevice>>();
+    assert_send_sync::<Journal<'_>>();
+};
+
+/// Directory the walk has yet to list
+struct PendingDir {
+    i_no: u64,
+    path: Vec<u8>,
+}
+
+/// A poisoned lock only means a callback panicked, which the scope propagates
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Hands the results of tasks finished in any order to `deliver` in task order
+struct Reorder<T> {
+    /// next task to deliver and the results of the tasks after it
+    state: Mutex<(usize, BTreeMap<usize, T>)>,
+}
+
+impl<T> Reorder<T> {
+    fn new() -> Self {
+        Self { state: Mutex::new((0, BTreeMap::new())) }
+    }
+
+    /// Every task must be submitted once, results after a missing one are held
+    fn submit(&self, task: usize, result: T, mut deliver: impl FnMut(T)) {
+        let mut state = lock(&self.state);
+        let (next, held) = &mut *state;
+        held.insert(task, result);
+        while let Some(result) = held.remove(next) {
+            *next += 1;
+            deliver(result);
+        }
+    }
+}
+
+/// Runs `task` for every index below `tasks` on up to `workers` threads, the
+/// calling thread being one of them. Tasks not started when `stop` is set are
+/// skipped.
+fn run_tasks(workers: usize, tasks: usize, stop: &AtomicBool, task: impl Fn(usize) + Sync) {
+    let next = AtomicUsize::new(0);
+    let work = || {
+        while !stop.load(Ordering::Relaxed) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            if index >= tasks {
+                break;
+            }
+            task(index);
+        }
+    };
+    thread::scope(|scope| {
+        for _ in 1..workers.min(tasks) {
+            scope.spawn(work);
+        }
+        work();
+    });
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Walks the tree under the directory `root` on several threads and calls `f`
+    /// for every entry but `.` and `..`, which may come from any worker. The tree
+    /// is walked breadth first: directories of one depth are listed in parallel
+    /// before the next. Each directory is listed once, even if entries of a
+    /// corrupt tree lead back to it.
+    pub fn walk(&self, root: u64, options: &WalkOptions, f: impl Fn(WalkEntry) + Sync) {
+        self.walk_while(root, options, |entry| {
+            f(entry);
+            true
+        })
+    }
+
+    /// `walk` delivering to a channel from a background thread. The walk stops
+    /// early when the receiver is dropped.
+    pub fn walk_channel(self: Arc<Self>, root: u64, options: WalkOptions) -> Receiver<WalkEntry>
+    where
+        D: 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+        thread::spawn(move || self.walk_while(root, &options, |entry| sender.send(entry).is_ok()));
+        receiver
+    }
+
+    /// `walk` stopping once `f` returns false
+    fn walk_while(&self, root: u64, options: &WalkOptions, f: impl Fn(WalkEntry) -> bool + Sync) {
+        let workers = options.workers();
+        let stop = AtomicBool::new(false);
+        let visited = Mutex::new(HashSet::from([root]));
+        let mut level = vec![PendingDir { i_no: root, path: Vec::new() }];
+        while !level.is_empty() && !stop.load(Ordering::Relaxed) {
+            let next_level = Mutex::new(Vec::new());
+            // queues the subdirectories not seen yet and delivers the entries
+            let deliver = |entries: Vec<WalkEntry>| {
+                let subdirs = {
+                    let mut visited = lock(&visited);
+                    entries
+                        .iter()
+                        .filter(|entry| entry.is_dir() && visited.insert(entry.i_no))
+                        .map(|entry| PendingDir { i_no: entry.i_no, path: entry.path.clone() })
+                        .collect::<Vec<_>>()
+                };
+                lock(&next_level).extend(subdirs);
+                for entry in entries {
+                    if stop.load(Ordering::Relaxed) || !f(entry) {
+                        stop.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            };
+            let reorder = Reorder::new();
+            run_tasks(workers, level.len(), &stop, |index| {
+                let entries = self.walk_entries(&level[index]);
+                if options.ordered {
+                    reorder.submit(index, entries, &deliver);
+                } else {
+                    deliver(entries);
+                }
+            });
+            level = mem::take(&mut *lock(&next_level));
+        }
+    }
+
+    /// Entries of a directory, in the order its entry chain holds them
+    fn walk_entries(&self, dir: &PendingDir) -> Vec<WalkEntry> {
+        let Some(BlockContents::Dentries(entries)) =
+            self.get_inode(dir.i_no).and_then(|inode| self.get_inode_block_contents(&inode))
+        else {
+            return Vec::new();
+        };
+        entries
+            .filter(|d_entry| d_entry.inode != 0 && !matches!(d_entry.name_bytes(), b"." | b".."))
+            .map(|d_entry| {
+                let mut path = Vec::with_capacity(dir.path.len() + 1 + d_entry.name_len as usize);
+                path.extend_from_slice(&dir.path);
+                path.push(b'/');
+                path.extend_from_slice(d_entry.name_bytes());
+                let i_no = d_entry.inode as u64;
+                WalkEntry { path, parent: dir.i_no, i_no, file_type: d_entry.file_type, inode: self.get_inode(i_no) }
+            })
+            .collect()
+    }
+
+    /// Calls `f` for every inode the inode bitmaps mark in use, on several threads.
+    /// Each worker takes a flex group at a time, whose inode tables lie next to
+    /// each other, and reads the tables block by block past the cache, which a
+    /// full scan would only flush. Groups flagged `INODE_UNINIT` are skipped.
+    pub fn scan_inodes(&self, options: &WalkOptions, f: impl Fn(Ext4Inode) + Sync) {
+        self.scan_inodes_while(options, |inode| {
+            f(inode);
+            true
+        })
+    }
+
+    /// `scan_inodes` delivering to a channel from a background thread. The scan
+    /// stops early when the receiver is dropped.
+    pub fn scan_inodes_channel(self: Arc<Self>, options: WalkOptions) -> Receiver<Ext4Inode>
+    where
+        D: 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+        thread::spawn(move || self.scan_inodes_while(&options, |inode| sender.send(inode).is_ok()));
+        receiver
+    }
+
+    /// `scan_inodes` stopping once `f` returns false
+    fn scan_inodes_while(&self, options: &WalkOptions, f: impl Fn(Ext4Inode) -> bool + Sync) {
+        let groups = self.group_descs.len();
+        let per_flex = self.super_block().groups_per_flex().clamp(1, groups.max(1) as u64) as usize;
+        let stop = AtomicBool::new(false);
+        let deliver = |inodes: Vec<Ext4Inode>| {
+            for inode in inodes {
+                if stop.load(Ordering::Relaxed) || !f(inode) {
+                    stop.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+        };
+        let reorder = Reorder::new();
+        run_tasks(options.workers(), groups.div_ceil(per_flex), &stop, |flex| {
+            for group in flex * per_flex..((flex + 1) * per_flex).min(groups) {
+                let inodes = self.group_inodes(group);
+                if options.ordered {
+                    reorder.submit(group, inodes, &deliver);
+                } else {
+                    deliver(inodes);
+                }
+            }
+        });
+    }
+
+    /// Inodes of `group` the bitmap marks in use, empty when the bitmap cannot be
+    /// read. A table block that cannot be read drops the inodes it holds.
+    fn group_inodes(&self, group: usize) -> Vec<Ext4Inode> {
+        let group_desc = &self.group_descs[group];
+        if group_desc.bg_flags.contains(BgFlags::INODE_UNINIT) {
+            return Vec::new();
+        }
+        let Some(bitmap) = self.get_block(group_desc.inode_bitmap()) else {
+            return Vec::new();
+        };
+        let super_block = self.super_block();
+        let inodes_per_group = super_block.s_inodes_per_group as u64;
+        let inode_size = super_block.s_inode_size as u64;
+        let block_size = self.block_size();
+        let mut table_block = None;
+        let mut inodes = Vec::new();
+        for index in 0..inodes_per_group {
+            if bitmap.get((index / 8) as usize).is_none_or(|byte| byte >> (index % 8) & 1 == 0) {
+                continue;
+            }
+            let offset = index * inode_size;
+            let block = group_desc.inode_table() + offset / block_size;
+            if table_block.as_ref().is_none_or(|(loaded, _)| *loaded != block) {
+                table_block = Some((block, self.get_data_block(block)));
+            }
+            let start = (offset % block_size) as usize;
+            let raw = table_block.as_ref().and_then(|(_, data)| data.as_deref()?.get(start..start + inode_size as usize));
+            if let Some(mut inode) = raw.and_then(Ext4Inode::parse_sized) {
+                inode.i_no = group as u64 * inodes_per_group + index + 1;
+                inodes.push(inode);
+            }
+        }
+        inodes
+    }
+}
@@ -0,0 +1,161 @@
+use crate::defs::{Ext4Inode, Ext4XattrEntry, Ext4XattrHeader, IncompatFeatures, EXT4_GOOD_OLD_INODE_SIZE};
+use crate::device::BlockDevice;
+use crate::fs_parser::Ext4Fs;
+use nom_derive::Parse;
+
+pub const EXT4_XATTR_MAGIC: u32 = 0xEA02_0000;
+
+pub const EXT4_XATTR_INDEX_USER: u8 = 1;
+pub const EXT4_XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+pub const EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+pub const EXT4_XATTR_INDEX_TRUSTED: u8 = 4;
+pub const EXT4_XATTR_INDEX_LUSTRE: u8 = 5;
+pub const EXT4_XATTR_INDEX_SECURITY: u8 = 6;
+pub const EXT4_XATTR_INDEX_SYSTEM: u8 = 7;
+pub const EXT4_XATTR_INDEX_RICHACL: u8 = 8;
+pub const EXT4_XATTR_INDEX_ENCRYPTION: u8 = 9;
+/// Largest value the kernel stores, in an EA inode
+pub const EXT4_XATTR_SIZE_MAX: usize = 1 << 24;
+/// Size of `Ext4XattrEntry` without the name
+const EXT4_XATTR_ENTRY_SIZE: usize = 16;
+
+/// Size of `Ext4XattrHeader` in front of the entries of an attribute block
+const EXT4_XATTR_HEADER_SIZE: usize = 32;
+
+/// A decoded extended attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    /// full name including the namespace prefix, e.g. `user.mime_type`
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Namespace prefix of a name index, the ACL indices carry the whole name
+pub fn name_prefix(name_index: u8) -> &'static str {
+    match name_index {
+        EXT4_XATTR_INDEX_USER => "user.",
+        EXT4_XATTR_INDEX_POSIX_ACL_ACCESS => "system.posix_acl_access",
+        EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT => "system.posix_acl_default",
+        EXT4_XATTR_INDEX_TRUSTED => "trusted.",
+        EXT4_XATTR_INDEX_LUSTRE => "lustre.",
+        EXT4_XATTR_INDEX_SECURITY => "security.",
+        EXT4_XATTR_INDEX_SYSTEM => "system.",
+        EXT4_XATTR_INDEX_RICHACL => "system.richacl",
+        EXT4_XATTR_INDEX_ENCRYPTION => "encryption.",
+        _ => "",
+    }
+}
+
+/// An attribute entry together with its name and value bytes
+#[derive(Debug, Clone, Copy)]
+pub struct XattrRef<'a> {
+    pub entry: Ext4XattrEntry,
+    pub name: &'a [u8],
+    /// empty when the value lives in an EA inode
+    pub value: &'a [u8],
+}
+
+/// Parses entries until the terminating zero word, values are located
+/// relative to `value_base`.
+pub fn parse_entries<'a>(mut entries: &'a [u8], value_base: &'a [u8]) -> Vec<XattrRef<'a>> {
+    let mut ret = Vec::new();
+    while entries.len() >= EXT4_XATTR_ENTRY_SIZE && entries[..4] != [0; 4] {
+        let Ok((rest, entry)) = Ext4XattrEntry::parse(entries) else {
+            break;
+        };
+        let Some(name) = rest.get(..entry.e_name_len as usize) else {
+            break;
+        };
+        let value = if entry.e_value_inum != 0 {
+            &[][..]
+        } else {
+            let start = entry.e_value_offs as usize;
+            match value_base.get(start..start + entry.e_value_size as usize) {
+                Some(value) => value,
+                None => break,
+            }
+        };
+        ret.push(XattrRef { entry, name, value });
+
+        let entry_len = (EXT4_XATTR_ENTRY_SIZE + entry.e_name_len as usize).next_multiple_of(4);
+        entries = entries.get(entry_len..).unwrap_or_default();
+    }
+    ret
+}
+
+/// Attributes stored in the inode body after `i_extra_isize`
+pub fn ibody_entries(raw_inode: &[u8], extra_isize: u16) -> Vec<XattrRef<'_>> {
+    let start = EXT4_GOOD_OLD_INODE_SIZE + extra_isize as usize;
+    let Some(magic) = raw_inode.get(start..start + 4) else {
+        return Vec::new();
+    };
+    if u32::from_le_bytes(magic.try_into().unwrap()) != EXT4_XATTR_MAGIC {
+        return Vec::new();
+    }
+    let entries = &raw_inode[start + 4..];
+    parse_entries(entries, entries)
+}
+
+/// Attributes stored in an external attribute block
+pub fn block_entries(block: &[u8]) -> Vec<XattrRef<'_>> {
+    match Ext4XattrHeader::parse(block) {
+        Ok((_, header)) if header.h_magic == EXT4_XATTR_MAGIC && header.h_blocks == 1 => {
+            parse_entries(&block[EXT4_XATTR_HEADER_SIZE..], block)
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Value of the `system.data` attribute holding inline data past `i_block`
+pub fn inline_data_value(raw_inode: &[u8], extra_isize: u16) -> Option<&[u8]> {
+    ibody_entries(raw_inode, extra_isize)
+        .into_iter()
+        .find(|xattr| xattr.entry.e_name_index == EXT4_XATTR_INDEX_SYSTEM && xattr.name == b"data")
+        .map(|xattr| xattr.value)
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Lists the attributes of an inode, in-inode ones first and then the
+    /// ones of the external attribute block.
+    pub fn xattrs(&self, inode: &Ext4Inode) -> Vec<Xattr> {
+        let mut xattrs = Vec::new();
+        if let Some(raw) = self.get_inode_bytes(inode.i_no) {
+            let entries = ibody_entries(&raw, inode.i_extra_isize);
+            xattrs.extend(entries.into_iter().filter_map(|xattr| self.decode_xattr(xattr)));
+        }
+        if inode.file_acl() != 0
+            && let Some(block) = self.get_block(inode.file_acl())
+        {
+            xattrs.extend(block_entries(&block).into_iter().filter_map(|xattr| self.decode_xattr(xattr)));
+        }
+        xattrs
+    }
+
+    /// Value of the attribute called `name`, e.g. `security.selinux`
+    pub fn get_xattr(&self, inode: &Ext4Inode, name: &str) -> Option<Vec<u8>> {
+        self.xattrs(inode).into_iter().find(|xattr| xattr.name == name).map(|xattr| xattr.value)
+    }
+
+    fn decode_xattr(&self, xattr: XattrRef) -> Option<Xattr> {
+        let mut name = name_prefix(xattr.entry.e_name_index).to_string();
+        name.push_str(&String::from_utf8_lossy(xattr.name));
+        let value = if xattr.entry.e_value_inum != 0 {
+            if !self.super_block().s_feature_incompat.contains(IncompatFeatures::EA_INODE) {
+                return None;
+            }
+            let size = xattr.entry.e_value_size as usize;
+            if size > EXT4_XATTR_SIZE_MAX {
+                return None;
+            }
+            let ea_inode = self.get_inode(xattr.entry.e_value_inum as u64)?;
+            // sized by the entry, the EA inode's `i_size` may be corrupt
+            let mut value = vec![0; size];
+            let len = self.read_at(&ea_inode, 0, &mut value)?;
+            value.truncate(len);
+            value
+        } else {
+            xattr.value.to_vec()
+        };
+        Some(Xattr { name, value })
+    }
+}